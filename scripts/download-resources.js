@@ -26,10 +26,6 @@ const ffprobeBinary = path.join(
     binaryDir,
     `ffprobe-${targetTriple}${extension}`
 );
-const organizeBinary = path.join(
-    binaryDir,
-    `organize-${targetTriple}${extension}`
-);
 
 const libDir = path.join(__dirname, "..", "src-tauri", "lib");
 
@@ -94,49 +90,6 @@ function getFFmpegInfo() {
     }
 }
 
-function getOrganizeInfo() {
-    const rustInfo = execSync("rustc -vV");
-    const targetTriple = /host: (\S+)/g.exec(rustInfo)[1];
-
-    if (targetTriple === "x86_64-pc-windows-msvc") {
-        return {
-            url: "https://github.com/simulacraliasing/organize/releases/download/v0.1.0/organize-x86_64-pc-windows-msvc.exe",
-            outputPath: path.join(
-                binaryDir,
-                "organize-x86_64-pc-windows-msvc.exe"
-            ),
-        };
-    } else if (targetTriple === "aarch64-apple-darwin") {
-        return {
-            url: "https://github.com/simulacraliasing/organize/releases/download/v0.1.0/organize-aarch64-apple-darwin",
-            outputPath: path.join(binaryDir, "organize-aarch64-apple-darwin"),
-        };
-    } else if (targetTriple === "x86_64-apple-darwin") {
-        return {
-            url: "https://github.com/simulacraliasing/organize/releases/download/v0.1.0/organize-x86_64-apple-darwin",
-            outputPath: path.join(binaryDir, "organize-x86_64-apple-darwin"),
-        };
-    } else if (targetTriple === "x86_64-unknown-linux-gnu") {
-        return {
-            url: "https://github.com/simulacraliasing/organize/releases/download/v0.1.0/organize-x86_64-unknown-linux-gnu",
-            outputPath: path.join(
-                binaryDir,
-                "organize-x86_64-unknown-linux-gnu"
-            ),
-        };
-    } else if (targetTriple === "aarch64-unknown-linux-gnu") {
-        return {
-            url: "https://github.com/simulacraliasing/organize/releases/download/v0.1.0/organize-aarch64-unknown-linux-gnu",
-            outputPath: path.join(
-                binaryDir,
-                "organize-aarch64-unknown-linux-gnu"
-            ),
-        };
-    } else {
-        throw new Error(`Unsupported target triple: ${targetTriple}`);
-    }
-}
-
 function getOrtInfo(targetTriple) {
     if (targetTriple == "x86_64-pc-windows-msvc") {
         return {
@@ -405,33 +358,6 @@ async function downloadFFmpeg() {
     }
 }
 
-async function downloadOrganize() {
-    if (fs.existsSync(organizeBinary)) {
-        console.log("Organize already exists, skipping download");
-        return;
-    }
-
-    const { url, outputPath } = getOrganizeInfo();
-
-    try {
-        console.log(`Downloading Organize for ${targetTriple} from ${url}...`);
-        await downloadFile(url, outputPath);
-        console.log("Download complete!");
-
-        if (targetTriple !== "x86_64-pc-windows-msvc") {
-            // Make the downloaded file executable
-            execSync(`chmod +x ${outputPath}`);
-        }
-
-        console.log(
-            "Organize has been successfully installed for your platform!"
-        );
-    } catch (error) {
-        console.error("Error downloading Organize:", error);
-        process.exit(1);
-    }
-}
-
 async function downloadOrt(targetTriple) {
     const { url, outputPath } = getOrtInfo(targetTriple);
     try {
@@ -471,6 +397,4 @@ async function downloadOrt(targetTriple) {
 // Run the download process
 downloadFFmpeg();
 
-downloadOrganize();
-
 downloadOrt(targetTriple);
@@ -0,0 +1,552 @@
+use ndarray::{array, s, Array2, Array4, ArrayView2, ArrayView3, ArrayViewMut3, Axis, Ix3};
+use ort::{inputs, Session, SessionOutputs, ValueType};
+use serde::{Deserialize, Serialize};
+
+use crate::detect::{DetectConfig, DetectError};
+use crate::media::Frame;
+use crate::utils::{nms, Bbox};
+
+/// Output layout of a detection model, set by `architecture` in the model toml.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Architecture {
+    /// YOLO exported with NMS, `output0` is `[batch, N, 6]` of
+    /// `[x1, y1, x2, y2, score, class]` in letterboxed input coordinates
+    YoloNms,
+    /// RT-DETR taking `orig_target_sizes`, `output0` is `[batch, N, 6]` in
+    /// original image coordinates
+    #[serde(rename = "rtdetr")]
+    RtDetr,
+    /// Raw YOLOv5 head, `output0` is `[batch, N, 5 + classes]` of
+    /// `[cx, cy, w, h, objectness, class scores...]`
+    Yolov5,
+    /// Raw YOLOv8 head, `output0` is `[batch, 4 + classes, N]` of
+    /// `[cx, cy, w, h, class scores...]`
+    Yolov8,
+}
+
+impl Architecture {
+    /// Architecture of model tomls written before `architecture` existed.
+    pub fn from_model_name(name: &str) -> Self {
+        if name.contains("rtdetr") {
+            Architecture::RtDetr
+        } else {
+            Architecture::YoloNms
+        }
+    }
+
+    pub fn default_layout(&self) -> OutputLayout {
+        match self {
+            Architecture::Yolov8 => OutputLayout::ValuesBoxes,
+            _ => OutputLayout::BoxesValues,
+        }
+    }
+}
+
+/// Axis order of the detection output after the batch axis.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputLayout {
+    /// `[batch, boxes, values]`
+    BoxesValues,
+    /// `[batch, values, boxes]`
+    ValuesBoxes,
+}
+
+/// Tensor names of the model, `[tensors]` in the model toml.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TensorConfig {
+    /// `[batch, 3, imgsz, imgsz]` image input
+    pub input: String,
+    /// `[batch, 2]` original size input of RT-DETR
+    pub size_input: String,
+    pub output: String,
+    /// Defaults to the architecture's layout
+    pub layout: Option<OutputLayout>,
+}
+
+impl Default for TensorConfig {
+    fn default() -> Self {
+        Self {
+            input: "images".to_string(),
+            size_input: "orig_target_sizes".to_string(),
+            output: "output0".to_string(),
+            layout: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelOrder {
+    #[default]
+    Rgb,
+    Bgr,
+}
+
+/// Input normalization, `[preprocess]` in the model toml. Pixels are scaled
+/// to 0–1 first, then `(x - mean) / std` per channel in the model's order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Preprocess {
+    pub channel_order: ChannelOrder,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    /// Letterbox color in 0–1 RGB
+    pub pad_color: [f32; 3],
+}
+
+impl Default for Preprocess {
+    fn default() -> Self {
+        Self {
+            channel_order: ChannelOrder::Rgb,
+            mean: [0.0; 3],
+            std: [1.0; 3],
+            pad_color: [0.44; 3],
+        }
+    }
+}
+
+/// Check the model's inputs and outputs against its config, so a wrong toml
+/// fails at load time instead of on every batch.
+pub fn validate_session(
+    session: &Session,
+    architecture: Architecture,
+    tensors: &TensorConfig,
+    imgsz: usize,
+) -> Result<(), String> {
+    let input_names: Vec<&str> = session.inputs.iter().map(|i| i.name.as_str()).collect();
+    let output_names: Vec<&str> = session.outputs.iter().map(|o| o.name.as_str()).collect();
+
+    let input = session
+        .inputs
+        .iter()
+        .find(|i| i.name == tensors.input)
+        .ok_or_else(|| {
+            format!(
+                "no input named {:?}, model inputs are {:?}",
+                tensors.input, input_names
+            )
+        })?;
+    if let ValueType::Tensor { dimensions, .. } = &input.input_type {
+        // Dynamic axes are -1
+        let expected = [-1, 3, imgsz as i64, imgsz as i64];
+        let matches = dimensions.len() == 4
+            && dimensions
+                .iter()
+                .zip(expected)
+                .all(|(&actual, expected)| actual < 0 || expected < 0 || actual == expected);
+        if !matches {
+            return Err(format!(
+                "input {:?} has shape {:?}, expected [batch, 3, {}, {}]",
+                tensors.input, dimensions, imgsz, imgsz
+            ));
+        }
+    }
+    if architecture == Architecture::RtDetr && !input_names.contains(&tensors.size_input.as_str()) {
+        return Err(format!(
+            "no input named {:?}, model inputs are {:?}",
+            tensors.size_input, input_names
+        ));
+    }
+    if !output_names.contains(&tensors.output.as_str()) {
+        return Err(format!(
+            "no output named {:?}, model outputs are {:?}",
+            tensors.output, output_names
+        ));
+    }
+    Ok(())
+}
+
+/// One letterboxed model input, a whole frame or a tile of it.
+pub struct ModelInput<'a> {
+    /// `[3, imgsz, imgsz]` 0–1 RGB
+    pub data: ArrayView3<'a, f32>,
+    /// Size of the region in the original image
+    pub width: usize,
+    pub height: usize,
+    pub padding: (usize, usize),
+    /// Original size / input size
+    pub ratio: f32,
+}
+
+impl<'a> From<&'a Frame> for ModelInput<'a> {
+    fn from(frame: &'a Frame) -> Self {
+        Self {
+            data: frame.data.view(),
+            width: frame.width,
+            height: frame.height,
+            padding: frame.padding,
+            ratio: frame.ratio,
+        }
+    }
+}
+
+/// Runs a batch through a model and turns its outputs into boxes in the
+/// coordinates of each input's region.
+pub trait ModelAdapter: Send + Sync {
+    fn infer(
+        &self,
+        inputs: &[ModelInput],
+        model: &Session,
+        config: &DetectConfig,
+    ) -> Result<Vec<Vec<Bbox>>, DetectError>;
+}
+
+pub fn model_adapter(architecture: Architecture) -> Box<dyn ModelAdapter> {
+    match architecture {
+        Architecture::YoloNms => Box::new(YoloNmsAdapter),
+        Architecture::RtDetr => Box::new(RtDetrAdapter),
+        Architecture::Yolov5 => Box::new(YoloRawAdapter { objectness: true }),
+        Architecture::Yolov8 => Box::new(YoloRawAdapter { objectness: false }),
+    }
+}
+
+pub struct YoloNmsAdapter;
+
+pub struct RtDetrAdapter;
+
+pub struct YoloRawAdapter {
+    objectness: bool,
+}
+
+fn inference_error(e: ort::Error) -> DetectError {
+    DetectError::Inference(e.to_string())
+}
+
+/// Write a 0–1 RGB `[3, H, W]` image into `target` in the model's channel
+/// order and normalization.
+pub(crate) fn normalize_into(
+    mut target: ArrayViewMut3<f32>,
+    image: ArrayView3<f32>,
+    preprocess: &Preprocess,
+) {
+    for c in 0..3 {
+        let source = match preprocess.channel_order {
+            ChannelOrder::Rgb => c,
+            ChannelOrder::Bgr => 2 - c,
+        };
+        let (mean, std) = (preprocess.mean[c], preprocess.std[c]);
+        target
+            .slice_mut(s![c, .., ..])
+            .assign(&image.slice(s![source, .., ..]).mapv(|x| (x - mean) / std));
+    }
+}
+
+/// Stack inputs into the model input, applying channel order and mean/std.
+fn batch_input(inputs: &[ModelInput], config: &DetectConfig) -> Array4<f32> {
+    let target_size = config.target_size;
+    let mut batch = Array4::<f32>::zeros((inputs.len(), 3, target_size, target_size));
+    for (i, input) in inputs.iter().enumerate() {
+        normalize_into(
+            batch.slice_mut(s![i, .., .., ..]),
+            input.data,
+            &config.preprocess,
+        );
+    }
+    batch
+}
+
+/// Detection output as `[batch, boxes, values]` whatever the model's layout.
+fn detections<'a>(
+    outputs: &'a SessionOutputs,
+    config: &DetectConfig,
+    batch_size: usize,
+    min_values: usize,
+) -> Result<ArrayView3<'a, f32>, DetectError> {
+    let output = outputs
+        .get(config.tensors.output.as_str())
+        .ok_or_else(|| DetectError::InvalidOutput(format!("missing {}", config.tensors.output)))?
+        .try_extract_tensor::<f32>()
+        .map_err(|e| DetectError::InvalidOutput(e.to_string()))?
+        .into_dimensionality::<Ix3>()
+        .map_err(|e| DetectError::InvalidOutput(format!("expected a 3D output: {}", e)))?;
+    let layout = config
+        .tensors
+        .layout
+        .unwrap_or_else(|| config.architecture.default_layout());
+    let output = match layout {
+        OutputLayout::BoxesValues => output,
+        OutputLayout::ValuesBoxes => output.permuted_axes([0, 2, 1]),
+    };
+    if output.shape()[0] != batch_size || output.shape()[2] < min_values {
+        return Err(DetectError::InvalidOutput(format!(
+            "unexpected output shape {:?}",
+            output.shape()
+        )));
+    }
+    Ok(output)
+}
+
+/// Clamp a box to the input's region and build a `Bbox`.
+fn clamp_box(input: &ModelInput, coords: [f32; 4], score: f32, class: usize) -> Bbox {
+    let (width, height) = (input.width as f32, input.height as f32);
+    Bbox {
+        class,
+        score,
+        x1: coords[0].max(0.0).min(width),
+        y1: coords[1].max(0.0).min(height),
+        x2: coords[2].max(0.0).min(width),
+        y2: coords[3].max(0.0).min(height),
+        species: None,
+    }
+}
+
+/// Map letterboxed input coordinates back to the original image.
+fn unletterbox(input: &ModelInput, x1: f32, y1: f32, x2: f32, y2: f32) -> [f32; 4] {
+    let (pad_w, pad_h) = (input.padding.0 as f32, input.padding.1 as f32);
+    [
+        (x1 - pad_w) * input.ratio,
+        (y1 - pad_h) * input.ratio,
+        (x2 - pad_w) * input.ratio,
+        (y2 - pad_h) * input.ratio,
+    ]
+}
+
+impl ModelAdapter for YoloNmsAdapter {
+    fn infer(
+        &self,
+        inputs: &[ModelInput],
+        model: &Session,
+        config: &DetectConfig,
+    ) -> Result<Vec<Vec<Bbox>>, DetectError> {
+        let batch = batch_input(inputs, config);
+        let outputs = model
+            .run(inputs![config.tensors.input.as_str() => batch.view()].map_err(inference_error)?)
+            .map_err(inference_error)?;
+        let output = detections(&outputs, config, inputs.len(), 6)?;
+
+        let mut results = Vec::with_capacity(inputs.len());
+        for (i, input) in inputs.iter().enumerate() {
+            let mut boxes = Vec::new();
+            // [N, 6] of [x1, y1, x2, y2, prob, class_id]
+            for row in output.slice(s![i, .., ..]).axis_iter(Axis(0)) {
+                let prob = row[4];
+                if !config.accepts(row[5] as usize, prob) {
+                    continue;
+                }
+                let coords = unletterbox(input, row[0], row[1], row[2], row[3]);
+                boxes.push(clamp_box(input, coords, prob, row[5] as usize));
+            }
+            results.push(nms(&mut boxes, true, 100, config.iou_thres));
+        }
+        Ok(results)
+    }
+}
+
+impl ModelAdapter for RtDetrAdapter {
+    fn infer(
+        &self,
+        inputs: &[ModelInput],
+        model: &Session,
+        config: &DetectConfig,
+    ) -> Result<Vec<Vec<Bbox>>, DetectError> {
+        let batch = batch_input(inputs, config);
+        // The model scales boxes to a square of the longer side, padding included,
+        // which is the input size times the letterbox ratio
+        let mut orig_target_sizes = Array2::<i64>::zeros((inputs.len(), 2));
+        for (i, input) in inputs.iter().enumerate() {
+            let size = (config.target_size as f32 * input.ratio).round() as i64;
+            orig_target_sizes
+                .slice_mut(s![i, ..])
+                .assign(&array![size, size]);
+        }
+        let outputs = model
+            .run(
+                inputs! {
+                    config.tensors.input.as_str() => batch.view(),
+                    config.tensors.size_input.as_str() => orig_target_sizes.view()
+                }
+                .map_err(inference_error)?,
+            )
+            .map_err(inference_error)?;
+        let output = detections(&outputs, config, inputs.len(), 6)?;
+
+        let mut results = Vec::with_capacity(inputs.len());
+        for (i, input) in inputs.iter().enumerate() {
+            let (pad_w, pad_h) = (
+                input.padding.0 as f32 * input.ratio,
+                input.padding.1 as f32 * input.ratio,
+            );
+            let mut boxes = Vec::new();
+            // [N, 6] of [x1, y1, x2, y2, prob, class_id] in original size
+            for row in output.slice(s![i, .., ..]).axis_iter(Axis(0)) {
+                let prob = row[4];
+                if !config.accepts(row[5] as usize, prob) {
+                    continue;
+                }
+                let coords = [
+                    row[0] - pad_w,
+                    row[1] - pad_h,
+                    row[2] - pad_w,
+                    row[3] - pad_h,
+                ];
+                boxes.push(clamp_box(input, coords, prob, row[5] as usize));
+            }
+            results.push(boxes);
+        }
+        Ok(results)
+    }
+}
+
+impl YoloRawAdapter {
+    /// Decode one frame's predictions, `predictions` is `[N, 4 + (1) + classes]`.
+    fn decode(
+        &self,
+        predictions: ArrayView2<f32>,
+        input: &ModelInput,
+        config: &DetectConfig,
+    ) -> Vec<Bbox> {
+        let class_offset = if self.objectness { 5 } else { 4 };
+        let mut boxes = Vec::new();
+        for row in predictions.axis_iter(Axis(0)) {
+            let objectness = if self.objectness { row[4] } else { 1.0 };
+            if objectness < config.min_conf_thres() {
+                continue;
+            }
+            let Some((class, class_score)) = row
+                .slice(s![class_offset..])
+                .iter()
+                .copied()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+            else {
+                continue;
+            };
+            let score = objectness * class_score;
+            if !config.accepts(class, score) {
+                continue;
+            }
+            let (cx, cy, w, h) = (row[0], row[1], row[2], row[3]);
+            let coords = unletterbox(
+                input,
+                cx - w / 2.0,
+                cy - h / 2.0,
+                cx + w / 2.0,
+                cy + h / 2.0,
+            );
+            boxes.push(clamp_box(input, coords, score, class));
+        }
+        nms(&mut boxes, true, 100, config.iou_thres)
+    }
+}
+
+impl ModelAdapter for YoloRawAdapter {
+    fn infer(
+        &self,
+        inputs: &[ModelInput],
+        model: &Session,
+        config: &DetectConfig,
+    ) -> Result<Vec<Vec<Bbox>>, DetectError> {
+        let batch = batch_input(inputs, config);
+        let outputs = model
+            .run(inputs![config.tensors.input.as_str() => batch.view()].map_err(inference_error)?)
+            .map_err(inference_error)?;
+        let min_values = if self.objectness { 6 } else { 5 };
+        let output = detections(&outputs, config, inputs.len(), min_values)?;
+
+        let mut results = Vec::with_capacity(inputs.len());
+        for (i, input) in inputs.iter().enumerate() {
+            results.push(self.decode(output.slice(s![i, .., ..]), input, config));
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::FileItem;
+    use ndarray::Array3;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    fn test_frame() -> Frame {
+        Frame {
+            file: FileItem::new(0, 0, PathBuf::from("a.jpg"), None),
+            data: Array3::zeros((3, 640, 640)),
+            width: 1280,
+            height: 960,
+            padding: (0, 80),
+            ratio: 2.0,
+            frame_index: 0,
+            total_frames: 1,
+            shoot_time: None,
+            shoot_time_source: None,
+            orientation: None,
+            iframe: false,
+            timestamp: None,
+            video_duration: None,
+            video_fps: None,
+            original: None,
+        }
+    }
+
+    fn test_config() -> DetectConfig {
+        DetectConfig {
+            ep: crate::utils::Ep::Cpu,
+            device: "cpu".to_string(),
+            model_path: PathBuf::new(),
+            target_size: 640,
+            class_map: HashMap::new(),
+            conf_thres: 0.2,
+            class_thresholds: HashMap::new(),
+            iou_thres: 0.45,
+            batch_size: 1,
+            timeout: 50,
+            model_name: "test".to_string(),
+            architecture: Architecture::Yolov8,
+            tensors: TensorConfig::default(),
+            preprocess: Preprocess::default(),
+            classifier: None,
+            tiling: None,
+        }
+    }
+
+    #[test]
+    fn test_decode_yolov8() {
+        // Two overlapping boxes of class 1 and a low score box, [N, 4 + 2]
+        let predictions = array![
+            [320.0, 320.0, 100.0, 100.0, 0.1, 0.9],
+            [322.0, 320.0, 100.0, 100.0, 0.1, 0.8],
+            [100.0, 200.0, 50.0, 50.0, 0.1, 0.1],
+        ];
+        let adapter = YoloRawAdapter { objectness: false };
+        let frame = test_frame();
+        let boxes = adapter.decode(predictions.view(), &(&frame).into(), &test_config());
+
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(boxes[0].class, 1);
+        assert_eq!(boxes[0].x1, 540.0);
+        assert_eq!(boxes[0].y1, 380.0);
+    }
+
+    #[test]
+    fn test_batch_input_bgr() {
+        let mut frame = test_frame();
+        frame.data.slice_mut(s![0, .., ..]).fill(1.0);
+        let mut config = test_config();
+        config.preprocess.channel_order = ChannelOrder::Bgr;
+        config.preprocess.mean = [0.0, 0.0, 0.5];
+        config.preprocess.std = [1.0, 1.0, 0.25];
+
+        let inputs = batch_input(&[(&frame).into()], &config);
+
+        // Red ends up last, normalized with the last mean/std
+        assert_eq!(inputs[[0, 0, 0, 0]], 0.0);
+        assert_eq!(inputs[[0, 2, 0, 0]], 2.0);
+    }
+
+    #[test]
+    fn test_architecture_from_model_name() {
+        assert_eq!(
+            Architecture::from_model_name("mdv6-rtdetr-x"),
+            Architecture::RtDetr
+        );
+        assert_eq!(
+            Architecture::from_model_name("mdv6-yolov9e"),
+            Architecture::YoloNms
+        );
+    }
+}
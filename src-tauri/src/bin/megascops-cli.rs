@@ -0,0 +1,537 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressStyle};
+use megascops_local_lib::camtrap;
+use megascops_local_lib::organize::{self, OrganizeMode, OrganizeOptions};
+use megascops_local_lib::report::{self, ReportOptions};
+use megascops_local_lib::sequence::SequenceOptions;
+use megascops_local_lib::utils::Ep;
+use megascops_local_lib::{
+    Config, EpConfig, ExportFormat, JobControl, Pipeline, PipelineObserver, RunSummary,
+    VideoSampling,
+};
+
+/// Headless Megascops, runs the same detection pipeline as the GUI.
+#[derive(Parser, Debug)]
+#[command(name = "megascops-cli", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Detect media in a folder and write result.json/result.csv into it
+    Detect(DetectArgs),
+    /// Check a Camtrap DP package against the Camtrap DP schemas, offline
+    ValidateCamtrapDp {
+        /// Folder with datapackage.json, e.g. the camtrap-dp folder of an export
+        dir: PathBuf,
+    },
+    /// Move media into class folders according to a result file
+    Organize(OrganizeArgs),
+    /// Move the files of the last organize of a media folder back
+    UndoOrganize {
+        /// Media folder that was organized
+        dir: PathBuf,
+    },
+    /// Count independent detections per camera and class and write events.csv and events.json
+    Report(ReportArgs),
+}
+
+#[derive(Args, Debug)]
+struct ReportArgs {
+    /// Result file or store in the media folder
+    result: PathBuf,
+
+    /// Minutes between independent detections of a class at a camera
+    #[arg(long, default_value_t = report::DEFAULT_INTERVAL)]
+    interval: u64,
+
+    /// Count species of classified boxes instead of detector classes
+    #[arg(long)]
+    species: bool,
+
+    /// Folder to write the report into, the media folder by default
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct OrganizeArgs {
+    /// Result file or store in the media folder
+    result: PathBuf,
+
+    /// Move all files of a sequence to the folder of its strongest class
+    #[arg(long)]
+    guess: bool,
+
+    /// Regroup sequences with this gap in seconds instead of the recorded ones
+    #[arg(long, requires = "guess")]
+    sequence_gap: Option<u64>,
+
+    /// How files get to their class folder
+    #[arg(long, value_enum, default_value_t = OrganizeMode::Move)]
+    mode: OrganizeMode,
+
+    /// Destination relative to the media folder, e.g. `{class}/{site}/{date:%Y-%m}/{filename}`
+    #[arg(long)]
+    template: Option<String>,
+
+    /// Only print what would be moved and what is in the way
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print the dry run plan as JSON instead of a summary
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct DetectArgs {
+    /// TOML file with `detectOptions` and `configOptions` tables, flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Media folder to process
+    #[arg(short, long)]
+    folder: Option<String>,
+
+    /// Model config toml, e.g. models/toml/MDV6-yolov9e_d_pp.toml
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// Result store (result.jsonl) or result file to resume from
+    #[arg(long)]
+    resume_path: Option<String>,
+
+    /// Execution provider as `EP[:ID[:WORKERS]]`, e.g. `cpu`, `cuda:0:2`, `openvino:gpu`.
+    /// Can be repeated to run on several devices
+    #[arg(long = "ep")]
+    ep: Vec<String>,
+
+    #[arg(long)]
+    confidence_threshold: Option<f32>,
+
+    #[arg(long)]
+    iou_threshold: Option<f32>,
+
+    /// json, csv, megadetector, coco or camtrap-dp
+    #[arg(long)]
+    export_format: Option<String>,
+
+    /// Max frames sampled per video
+    #[arg(long)]
+    max_frames: Option<usize>,
+
+    /// Sample N frames per second of video instead of `--max-frames` frames per video
+    #[arg(long, conflicts_with = "sample_interval")]
+    sample_fps: Option<f32>,
+
+    /// Sample one frame every N seconds of video instead of `--max-frames` frames per video
+    #[arg(long)]
+    sample_interval: Option<f32>,
+
+    /// Image extensions to process, comma separated, e.g. `jpg,heic,dng`
+    #[arg(long, value_delimiter = ',')]
+    image_extensions: Option<Vec<String>>,
+
+    /// Video extensions to process, comma separated, e.g. `mp4,mov`
+    #[arg(long, value_delimiter = ',')]
+    video_extensions: Option<Vec<String>>,
+
+    /// Only decode key frames of videos
+    #[arg(long, overrides_with = "all_frames")]
+    iframe_only: bool,
+
+    /// Decode all frames of videos, overriding `iframe_only` of the config file
+    #[arg(long, overrides_with = "iframe_only")]
+    all_frames: bool,
+
+    /// Save a checkpoint every N frames
+    #[arg(long)]
+    check_point: Option<usize>,
+
+    /// Copy media into this folder before decoding, useful for network drives
+    #[arg(long)]
+    buffer_path: Option<String>,
+
+    #[arg(long)]
+    buffer_size: Option<usize>,
+
+    #[arg(long)]
+    batch_size: Option<usize>,
+
+    /// Confidence threshold of one class as `CLASS=THRESHOLD`, e.g. `Person=0.5`.
+    /// Can be repeated
+    #[arg(long = "class-threshold", value_parser = parse_class_threshold)]
+    class_thresholds: Vec<(String, f32)>,
+
+    /// Only keep these classes, comma separated
+    #[arg(long, value_delimiter = ',')]
+    include_classes: Vec<String>,
+
+    /// Drop these classes, comma separated
+    #[arg(long, value_delimiter = ',')]
+    exclude_classes: Vec<String>,
+
+    /// Also detect on full resolution tiles of frames larger than the model input
+    #[arg(long)]
+    tiling: bool,
+
+    /// Overlap of neighbouring tiles as a fraction of the tile size
+    #[arg(long, requires = "tiling")]
+    tile_overlap: Option<f32>,
+
+    /// Skip the whole frame pass when tiling
+    #[arg(long, requires = "tiling")]
+    tiles_only: bool,
+
+    /// Species classifier toml, run on Animal crops
+    #[arg(long)]
+    classifier: Option<String>,
+
+    /// Seconds between stills of one sequence, per folder gaps go in the config file
+    #[arg(long)]
+    sequence_gap: Option<u64>,
+}
+
+fn parse_ep(value: &str) -> Result<EpConfig> {
+    let mut parts = value.split(':');
+    let ep = match parts.next().unwrap_or_default().to_lowercase().as_str() {
+        "cpu" => Ep::Cpu,
+        "cuda" => Ep::CUDA,
+        "tensorrt" => Ep::TensorRT,
+        "openvino" => Ep::OpenVINO,
+        "directml" => Ep::DirectML,
+        "coreml" => Ep::CoreML,
+        other => return Err(anyhow!("Unknown execution provider: {}", other)),
+    };
+    let id = match parts.next() {
+        Some(id) => id.to_string(),
+        None => match ep {
+            Ep::Cpu | Ep::CoreML => "cpu".to_string(),
+            Ep::OpenVINO => "cpu".to_string(),
+            _ => "0".to_string(),
+        },
+    };
+    let workers = match parts.next() {
+        Some(workers) => workers
+            .parse()
+            .with_context(|| format!("Invalid worker count in --ep {}", value))?,
+        None => 1,
+    };
+    Ok(EpConfig {
+        ep,
+        workers,
+        device: id.clone(),
+        id,
+    })
+}
+
+fn parse_class_threshold(value: &str) -> Result<(String, f32)> {
+    let (class, threshold) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected CLASS=THRESHOLD, got {}", value))?;
+    let threshold = threshold
+        .parse()
+        .with_context(|| format!("Invalid threshold in {}", value))?;
+    Ok((class.to_string(), threshold))
+}
+
+fn parse_export_format(value: &str) -> Result<ExportFormat> {
+    match value.to_lowercase().as_str() {
+        "json" => Ok(ExportFormat::Json),
+        "csv" => Ok(ExportFormat::Csv),
+        "megadetector" | "md" => Ok(ExportFormat::MegaDetector),
+        "coco" | "coco-cct" => Ok(ExportFormat::CocoCameraTraps),
+        "camtrap-dp" | "camtrapdp" => Ok(ExportFormat::CamtrapDp),
+        other => Err(anyhow!("Unknown export format: {}", other)),
+    }
+}
+
+fn build_config(args: DetectArgs) -> Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => {
+            let toml_str = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config {}", path.display()))?;
+            toml::from_str(&toml_str)
+                .with_context(|| format!("Failed to parse config {}", path.display()))?
+        }
+        None => Config::default(),
+    };
+
+    let detect_options = &mut config.detect_options;
+    if let Some(folder) = args.folder {
+        detect_options.selected_folder = folder;
+    }
+    if let Some(model) = args.model {
+        detect_options.model = model;
+    }
+    if args.resume_path.is_some() {
+        detect_options.resume_path = args.resume_path;
+    }
+    if !args.ep.is_empty() {
+        detect_options.ep = args
+            .ep
+            .iter()
+            .map(|ep| parse_ep(ep))
+            .collect::<Result<Vec<_>>>()?;
+    }
+
+    let config_options = &mut config.config_options;
+    if let Some(conf) = args.confidence_threshold {
+        config_options.confidence_threshold = conf;
+    }
+    if let Some(iou) = args.iou_threshold {
+        config_options.iou_threshold = iou;
+    }
+    if let Some(format) = args.export_format {
+        config_options.export_format = parse_export_format(&format)?;
+    }
+    if args.max_frames.is_some() {
+        config_options.max_frames = args.max_frames;
+    }
+    if let Some(fps) = args.sample_fps {
+        config_options.video_sampling = VideoSampling::Fps { fps };
+    }
+    if let Some(seconds) = args.sample_interval {
+        config_options.video_sampling = VideoSampling::Interval { seconds };
+    }
+    if let Some(images) = args.image_extensions {
+        config_options.extensions.images = images;
+    }
+    if let Some(videos) = args.video_extensions {
+        config_options.extensions.videos = videos;
+    }
+    if args.iframe_only {
+        config_options.iframe_only = true;
+    } else if args.all_frames {
+        config_options.iframe_only = false;
+    }
+    if let Some(check_point) = args.check_point {
+        config_options.check_point = check_point;
+    }
+    if args.buffer_path.is_some() {
+        config_options.buffer_path = args.buffer_path;
+    }
+    if let Some(buffer_size) = args.buffer_size {
+        config_options.buffer_size = buffer_size;
+    }
+    if let Some(batch_size) = args.batch_size {
+        config_options.batch_size = batch_size;
+    }
+    config_options
+        .class_thresholds
+        .extend(args.class_thresholds);
+    if !args.include_classes.is_empty() {
+        config_options.include_classes = args.include_classes;
+    }
+    if !args.exclude_classes.is_empty() {
+        config_options.exclude_classes = args.exclude_classes;
+    }
+    if args.tiling {
+        let mut tiling = config_options.tiling.unwrap_or_default();
+        if let Some(overlap) = args.tile_overlap {
+            tiling.overlap = overlap;
+        }
+        if args.tiles_only {
+            tiling.full_frame = false;
+        }
+        config_options.tiling = Some(tiling);
+    }
+    if args.classifier.is_some() {
+        config_options.classifier = args.classifier;
+    }
+    if let Some(gap) = args.sequence_gap {
+        config_options.sequence.gap = gap;
+    }
+
+    if config.detect_options.selected_folder.is_empty() {
+        return Err(anyhow!(
+            "No media folder given, use --folder or a config file"
+        ));
+    }
+    if config.detect_options.model.is_empty() {
+        return Err(anyhow!("No model given, use --model or a config file"));
+    }
+    Ok(config)
+}
+
+struct ProgressObserver {
+    bar: ProgressBar,
+}
+
+impl PipelineObserver for ProgressObserver {
+    fn on_start(&self, total_files: usize) {
+        self.bar.set_length(total_files as u64);
+    }
+
+    fn on_progress(&self, processed: usize, _total: usize) {
+        self.bar.set_position(processed as u64);
+    }
+
+    fn on_complete(&self, summary: &RunSummary) {
+        if summary.cancelled {
+            self.bar.abandon();
+        } else {
+            self.bar.finish();
+        }
+    }
+}
+
+async fn detect(config: Config) -> Result<()> {
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{spinner} [{elapsed_precise}] {bar:40} {pos}/{len} files ({eta})",
+        )?
+        .progress_chars("=> "),
+    );
+
+    // Ctrl-C stops feeding new files and writes a final checkpoint
+    let control = Arc::new(JobControl::new());
+    let ctrl_c_control = Arc::clone(&control);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::warn!("Interrupted, finishing in-flight batches");
+            ctrl_c_control.cancel();
+        }
+    });
+
+    let pipeline = Pipeline::builder()
+        .config(config)
+        .control(control)
+        .observer(Arc::new(ProgressObserver { bar: bar.clone() }))
+        .build()?;
+
+    let summary = tokio::task::spawn_blocking(move || pipeline.run())
+        .await?
+        .inspect_err(|_| bar.abandon())?;
+
+    if summary.cancelled {
+        return Err(anyhow!(
+            "Detection cancelled, resume with --resume-path {}",
+            summary.store_path.display()
+        ));
+    }
+    log::info!(
+        "Processed {} files ({} frames) in {:?}, results in {}",
+        summary.processed_files,
+        summary.frames,
+        summary.elapsed,
+        summary.result_path.display()
+    );
+    Ok(())
+}
+
+fn validate_camtrap_dp(dir: PathBuf) -> Result<()> {
+    let issues = camtrap::validate(&dir)
+        .with_context(|| format!("Failed to read Camtrap DP package {}", dir.display()))?;
+    if issues.is_empty() {
+        log::info!("{} is a valid Camtrap DP package", dir.display());
+        return Ok(());
+    }
+    for issue in &issues {
+        log::warn!("{}", issue);
+    }
+    Err(anyhow!("{} Camtrap DP issues found", issues.len()))
+}
+
+fn organize_media(args: OrganizeArgs) -> Result<()> {
+    let options = OrganizeOptions {
+        guess: args.guess,
+        sequence: args.sequence_gap.map(|gap| SequenceOptions {
+            gap,
+            ..Default::default()
+        }),
+        mode: args.mode,
+        template: args.template,
+    };
+    if args.dry_run {
+        let plan = organize::dry_run(&args.result, &options)?;
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        } else {
+            print!("{}", plan);
+        }
+        return Ok(());
+    }
+    let summary = organize::organize(&args.result, &options, &|_, _| {})?;
+    log::info!(
+        "{} files organized, {} skipped, {} failed",
+        summary.moved,
+        summary.skipped,
+        summary.failed
+    );
+    Ok(())
+}
+
+fn undo_organize(dir: PathBuf) -> Result<()> {
+    let summary = organize::undo(&dir, &|_, _| {})?;
+    if summary.failed > 0 {
+        return Err(anyhow!(
+            "{} files could not be moved back, run undo-organize again",
+            summary.failed
+        ));
+    }
+    Ok(())
+}
+
+fn event_report(args: ReportArgs) -> Result<()> {
+    let options = ReportOptions {
+        interval: args.interval,
+        species: args.species,
+    };
+    let report = report::report(&args.result, &options)?;
+    let dir = match args.output {
+        Some(dir) => dir,
+        None => std::fs::canonicalize(&args.result)?
+            .parent()
+            .ok_or_else(|| anyhow!("Result file has no parent folder"))?
+            .to_path_buf(),
+    };
+    report::write_report(&report, &dir)?;
+    let events: usize = report
+        .cameras
+        .iter()
+        .flat_map(|camera| &camera.classes)
+        .map(|class| class.events)
+        .sum();
+    log::info!(
+        "{} independent events at {} cameras, report written to {}",
+        events,
+        report.cameras.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Detect(args) => match build_config(args) {
+            Ok(config) => detect(config).await,
+            Err(e) => Err(e),
+        },
+        Command::ValidateCamtrapDp { dir } => validate_camtrap_dp(dir),
+        Command::Organize(args) => organize_media(args),
+        Command::UndoOrganize { dir } => undo_organize(dir),
+        Command::Report(args) => event_report(args),
+    };
+
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,1012 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, FixedOffset, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::export::{parse_shoot_time, ExportError, ExportFrame, RunMetadata};
+use crate::megadetector::relative_path;
+
+/// Camtrap DP version written, its profile and table schemas are checked by [`validate`].
+pub const CAMTRAP_DP_VERSION: &str = "1.0";
+
+/// Folder of the data package inside the media folder.
+pub const CAMTRAP_DP_DIR: &str = "camtrap-dp";
+
+/// Package level information that can't be derived from the media.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CamtrapDpOptions {
+    /// Project title, the media folder name when empty
+    pub title: String,
+    pub contributors: Vec<Contributor>,
+    /// Design of the camera placement, one of the Camtrap DP sampling designs
+    pub sampling_design: String,
+    /// Coordinates by deployment ID, which is the folder path relative to the media folder
+    pub deployments: BTreeMap<String, DeploymentLocation>,
+}
+
+impl Default for CamtrapDpOptions {
+    fn default() -> Self {
+        Self {
+            title: String::new(),
+            contributors: Vec::new(),
+            sampling_design: "opportunistic".to_string(),
+            deployments: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Contributor {
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeploymentLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres
+    pub coordinate_uncertainty: Option<u32>,
+    pub location_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Datetime,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Constraint {
+    None,
+    Enum(&'static [&'static str]),
+    /// Inclusive minimum and maximum
+    Range(f64, f64),
+    /// Prefix of a media type, e.g. `image/`
+    MediaType,
+}
+
+/// A field of a Camtrap DP table schema.
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    name: &'static str,
+    required: bool,
+    kind: FieldType,
+    constraint: Constraint,
+}
+
+const fn field(name: &'static str, kind: FieldType) -> Field {
+    Field {
+        name,
+        required: false,
+        kind,
+        constraint: Constraint::None,
+    }
+}
+
+const fn required(name: &'static str, kind: FieldType) -> Field {
+    Field {
+        name,
+        required: true,
+        kind,
+        constraint: Constraint::None,
+    }
+}
+
+const fn constrained(field: Field, constraint: Constraint) -> Field {
+    Field {
+        name: field.name,
+        required: field.required,
+        kind: field.kind,
+        constraint,
+    }
+}
+
+use FieldType::{Boolean, Datetime, Integer, Number, String as Text};
+
+const MAX: f64 = f64::MAX;
+
+const DEPLOYMENT_FIELDS: [Field; 24] = [
+    required("deploymentID", Text),
+    field("locationID", Text),
+    field("locationName", Text),
+    constrained(required("latitude", Number), Constraint::Range(-90.0, 90.0)),
+    constrained(
+        required("longitude", Number),
+        Constraint::Range(-180.0, 180.0),
+    ),
+    constrained(
+        field("coordinateUncertainty", Integer),
+        Constraint::Range(1.0, MAX),
+    ),
+    required("deploymentStart", Datetime),
+    required("deploymentEnd", Datetime),
+    field("setupBy", Text),
+    field("cameraID", Text),
+    field("cameraModel", Text),
+    constrained(field("cameraDelay", Integer), Constraint::Range(0.0, MAX)),
+    constrained(field("cameraHeight", Number), Constraint::Range(0.0, MAX)),
+    constrained(field("cameraDepth", Number), Constraint::Range(0.0, MAX)),
+    constrained(field("cameraTilt", Integer), Constraint::Range(-90.0, 90.0)),
+    constrained(
+        field("cameraHeading", Integer),
+        Constraint::Range(0.0, 360.0),
+    ),
+    constrained(
+        field("detectionDistance", Number),
+        Constraint::Range(0.0, MAX),
+    ),
+    field("timestampIssues", Boolean),
+    field("baitUse", Boolean),
+    constrained(
+        field("featureType", Text),
+        Constraint::Enum(&[
+            "roadPaved",
+            "roadDirt",
+            "trailHiking",
+            "trailGame",
+            "roadUnderpass",
+            "roadOverpass",
+            "roadBridge",
+            "culvert",
+            "burrow",
+            "nestSite",
+            "carcass",
+            "waterSource",
+            "fruitingTree",
+        ]),
+    ),
+    field("habitat", Text),
+    field("deploymentGroups", Text),
+    field("deploymentTags", Text),
+    field("deploymentComments", Text),
+];
+
+const MEDIA_FIELDS: [Field; 11] = [
+    required("mediaID", Text),
+    required("deploymentID", Text),
+    constrained(
+        field("captureMethod", Text),
+        Constraint::Enum(&["activityDetection", "timeLapse"]),
+    ),
+    required("timestamp", Datetime),
+    required("filePath", Text),
+    required("filePublic", Boolean),
+    field("fileName", Text),
+    constrained(required("fileMediatype", Text), Constraint::MediaType),
+    field("exifData", Text),
+    field("favorite", Boolean),
+    field("mediaComments", Text),
+];
+
+const OBSERVATION_FIELDS: [Field; 28] = [
+    required("observationID", Text),
+    required("deploymentID", Text),
+    field("mediaID", Text),
+    field("eventID", Text),
+    required("eventStart", Datetime),
+    required("eventEnd", Datetime),
+    constrained(
+        required("observationLevel", Text),
+        Constraint::Enum(&["media", "event"]),
+    ),
+    constrained(
+        required("observationType", Text),
+        Constraint::Enum(&[
+            "animal",
+            "human",
+            "vehicle",
+            "blank",
+            "unknown",
+            "unclassified",
+        ]),
+    ),
+    constrained(
+        field("cameraSetupType", Text),
+        Constraint::Enum(&["setup", "calibration"]),
+    ),
+    field("scientificName", Text),
+    constrained(field("count", Integer), Constraint::Range(1.0, MAX)),
+    constrained(
+        field("lifeStage", Text),
+        Constraint::Enum(&["adult", "subadult", "juvenile"]),
+    ),
+    constrained(field("sex", Text), Constraint::Enum(&["female", "male"])),
+    field("behavior", Text),
+    field("individualID", Text),
+    constrained(
+        field("individualPositionRadius", Number),
+        Constraint::Range(0.0, MAX),
+    ),
+    constrained(
+        field("individualPositionAngle", Number),
+        Constraint::Range(-90.0, 90.0),
+    ),
+    constrained(
+        field("individualSpeed", Number),
+        Constraint::Range(0.0, MAX),
+    ),
+    constrained(field("bboxX", Number), Constraint::Range(0.0, 1.0)),
+    constrained(field("bboxY", Number), Constraint::Range(0.0, 1.0)),
+    constrained(field("bboxWidth", Number), Constraint::Range(1e-15, 1.0)),
+    constrained(field("bboxHeight", Number), Constraint::Range(1e-15, 1.0)),
+    constrained(
+        field("classificationMethod", Text),
+        Constraint::Enum(&["human", "machine"]),
+    ),
+    field("classifiedBy", Text),
+    field("classificationTimestamp", Datetime),
+    constrained(
+        field("classificationProbability", Number),
+        Constraint::Range(0.0, 1.0),
+    ),
+    field("observationTags", Text),
+    field("observationComments", Text),
+];
+
+const SAMPLING_DESIGNS: [&str; 6] = [
+    "simpleRandom",
+    "systematicRandom",
+    "clusteredRandom",
+    "experimental",
+    "targeted",
+    "opportunistic",
+];
+
+/// Tables of the package as `(resource name, file, schema)`.
+const TABLES: [(&str, &str, &[Field]); 3] = [
+    ("deployments", "deployments.csv", &DEPLOYMENT_FIELDS),
+    ("media", "media.csv", &MEDIA_FIELDS),
+    ("observations", "observations.csv", &OBSERVATION_FIELDS),
+];
+
+type Row = HashMap<&'static str, String>;
+
+/// Camtrap DP tables and descriptor built from a run.
+#[derive(Debug)]
+pub struct CamtrapDp {
+    pub datapackage: serde_json::Value,
+    deployments: Vec<Row>,
+    media: Vec<Row>,
+    observations: Vec<Row>,
+}
+
+fn format_time(time: DateTime<FixedOffset>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Start and end of a file, a video ends its duration after its start.
+fn media_times(
+    frames: &[&ExportFrame],
+) -> (Option<DateTime<FixedOffset>>, Option<DateTime<FixedOffset>>) {
+    // Frame shoot times of videos are offset by their timestamp
+    let start = frames.iter().find_map(|frame| {
+        let time = parse_shoot_time(frame.shoot_time.as_deref()?)?;
+        let offset = Duration::milliseconds((frame.timestamp.unwrap_or(0.0) * 1000.0) as i64);
+        Some(time - offset)
+    });
+    let end = start.map(|start| {
+        let duration = frames.iter().find_map(|frame| frame.video_duration);
+        start + Duration::milliseconds((duration.unwrap_or(0.0) * 1000.0) as i64)
+    });
+    (start, end)
+}
+
+fn media_type(file_name: &str) -> String {
+    let extension = Path::new(file_name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "tif" | "tiff" => "image/tiff",
+        "webp" => "image/webp",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "dng" => "image/x-adobe-dng",
+        "mp4" => "video/mp4",
+        "avi" => "video/x-msvideo",
+        "mkv" => "video/x-matroska",
+        "mov" => "video/quicktime",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
+
+/// Observation type of a detector class, classes of other detectors are unclassified.
+fn observation_type(class: &str) -> &'static str {
+    match class.to_lowercase().as_str() {
+        "animal" => "animal",
+        "person" | "human" => "human",
+        "vehicle" => "vehicle",
+        _ => "unclassified",
+    }
+}
+
+fn round(value: f32) -> String {
+    format!("{:.4}", value.clamp(0.0, 1.0))
+}
+
+/// Build a data package from a run. Each folder with media is a deployment,
+/// its ID the folder path relative to the media folder and its location the
+/// first folder below the media folder.
+pub fn camtrap_dp(
+    export_data: &[ExportFrame],
+    metadata: &RunMetadata,
+    options: &CamtrapDpOptions,
+    folder_path: &Path,
+) -> CamtrapDp {
+    let classes = &metadata.info.model.classes;
+    let classified_by = match &metadata.info.classifier {
+        Some(classifier) => format!("{} + {}", metadata.info.model.name, classifier),
+        None => metadata.info.model.name.clone(),
+    };
+    let classification_time = metadata
+        .info
+        .end_time
+        .as_deref()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(format_time)
+        .unwrap_or_default();
+
+    let mut files: BTreeMap<String, Vec<&ExportFrame>> = BTreeMap::new();
+    for frame in export_data {
+        files
+            .entry(relative_path(&frame.file.file_path, folder_path))
+            .or_default()
+            .push(frame);
+    }
+    for frames in files.values_mut() {
+        frames.sort_by_key(|frame| frame.frame_index);
+    }
+    // An event spans all media of its sequence
+    let mut events: HashMap<&str, (DateTime<FixedOffset>, DateTime<FixedOffset>)> = HashMap::new();
+    for frames in files.values() {
+        if let (Some(event_id), (Some(start), Some(end))) =
+            (frames[0].sequence_id.as_deref(), media_times(frames))
+        {
+            events
+                .entry(event_id)
+                .and_modify(|(first, last)| {
+                    *first = (*first).min(start);
+                    *last = (*last).max(end);
+                })
+                .or_insert((start, end));
+        }
+    }
+
+    let root_name = folder_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "media".to_string());
+    let mut deployment_times: BTreeMap<String, Vec<DateTime<FixedOffset>>> = BTreeMap::new();
+    let mut media = Vec::new();
+    let mut observations = Vec::new();
+    let mut taxa = BTreeSet::new();
+
+    for (file_path, frames) in &files {
+        let deployment_id = match Path::new(&file_path).parent() {
+            Some(folder) if !folder.as_os_str().is_empty() => {
+                folder.to_string_lossy().replace('\\', "/")
+            }
+            _ => root_name.clone(),
+        };
+        let (start, end) = media_times(frames);
+        // Every folder with media is a deployment, even without shoot times
+        let times = deployment_times.entry(deployment_id.clone()).or_default();
+        times.extend(start);
+        times.extend(end);
+        let timestamp = start.map(format_time).unwrap_or_default();
+        let event_id = frames[0].sequence_id.clone().unwrap_or_default();
+        let (event_start, event_end) = match events.get(event_id.as_str()) {
+            Some(&(first, last)) => (format_time(first), format_time(last)),
+            None => (timestamp.clone(), end.map(format_time).unwrap_or_default()),
+        };
+
+        let media_id = file_path.clone();
+        media.push(Row::from([
+            ("mediaID", media_id.clone()),
+            ("deploymentID", deployment_id.clone()),
+            ("timestamp", timestamp.clone()),
+            ("filePath", file_path.clone()),
+            ("filePublic", "false".to_string()),
+            (
+                "fileName",
+                Path::new(&file_path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+            ),
+            ("fileMediatype", media_type(file_path)),
+        ]));
+
+        // Failed files have no observations
+        if frames.iter().all(|frame| frame.bboxes.is_none()) {
+            continue;
+        }
+        let mut observation = |kind: &str, extra: Vec<(&'static str, String)>| {
+            let mut row = Row::from([
+                (
+                    "observationID",
+                    format!("{}#{}", media_id, observations.len() + 1),
+                ),
+                ("deploymentID", deployment_id.clone()),
+                ("mediaID", media_id.clone()),
+                ("eventID", event_id.clone()),
+                ("eventStart", event_start.clone()),
+                ("eventEnd", event_end.clone()),
+                ("observationLevel", "media".to_string()),
+                ("observationType", kind.to_string()),
+                ("classificationMethod", "machine".to_string()),
+                ("classifiedBy", classified_by.clone()),
+                ("classificationTimestamp", classification_time.clone()),
+            ]);
+            row.extend(extra);
+            observations.push(row);
+        };
+
+        let is_video = frames.iter().any(|frame| frame.video_fps.is_some());
+        let bboxes: Vec<_> = frames
+            .iter()
+            .flat_map(|frame| frame.bboxes.iter().flatten().map(move |bbox| (frame, bbox)))
+            .collect();
+        if bboxes.is_empty() {
+            observation("blank", Vec::new());
+        } else if is_video {
+            // Boxes of a video are in different frames, count the most boxes
+            // of a class seen in one frame
+            let mut counts: BTreeMap<usize, (usize, f32)> = BTreeMap::new();
+            for frame in frames {
+                let mut frame_counts: HashMap<usize, (usize, f32)> = HashMap::new();
+                for bbox in frame.bboxes.iter().flatten() {
+                    let count = frame_counts.entry(bbox.class).or_default();
+                    count.0 += 1;
+                    count.1 = count.1.max(bbox.score);
+                }
+                for (class, (count, score)) in frame_counts {
+                    let total = counts.entry(class).or_default();
+                    total.0 = total.0.max(count);
+                    total.1 = total.1.max(score);
+                }
+            }
+            for (class, (count, score)) in counts {
+                let class = classes.get(class).map(String::as_str).unwrap_or_default();
+                let mut extra = vec![
+                    ("count", count.to_string()),
+                    ("classificationProbability", format!("{:.3}", score)),
+                ];
+                if observation_type(class) == "unclassified" {
+                    extra.push(("observationTags", format!("class:{}", class)));
+                }
+                observation(observation_type(class), extra);
+            }
+        } else {
+            for (frame, bbox) in bboxes {
+                let class = classes
+                    .get(bbox.class)
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                let species = bbox.species.as_ref().and_then(|species| species.first());
+                let mut extra = vec![
+                    ("count", "1".to_string()),
+                    (
+                        "classificationProbability",
+                        format!("{:.3}", species.map_or(bbox.score, |s| s.score)),
+                    ),
+                ];
+                if let Some(species) = species {
+                    taxa.insert(species.label.clone());
+                    extra.push(("scientificName", species.label.clone()));
+                }
+                if observation_type(class) == "unclassified" {
+                    extra.push(("observationTags", format!("class:{}", class)));
+                }
+                if let (Some(width), Some(height)) = (frame.width, frame.height) {
+                    let (width, height) = (width as f32, height as f32);
+                    extra.extend([
+                        ("bboxX", round(bbox.x1 / width)),
+                        ("bboxY", round(bbox.y1 / height)),
+                        ("bboxWidth", round((bbox.x2 - bbox.x1) / width)),
+                        ("bboxHeight", round((bbox.y2 - bbox.y1) / height)),
+                    ]);
+                }
+                observation(observation_type(class), extra);
+            }
+        }
+    }
+
+    let mut deployments = Vec::new();
+    let mut coordinates = Vec::new();
+    for (deployment_id, times) in &deployment_times {
+        let location_id = deployment_id
+            .split('/')
+            .next()
+            .unwrap_or(deployment_id)
+            .to_string();
+        let mut row = Row::from([
+            ("deploymentID", deployment_id.clone()),
+            ("locationID", location_id.clone()),
+            ("locationName", location_id),
+        ]);
+        if let (Some(start), Some(end)) = (times.iter().min(), times.iter().max()) {
+            row.insert("deploymentStart", format_time(*start));
+            row.insert("deploymentEnd", format_time(*end));
+        }
+        if let Some(location) = options.deployments.get(deployment_id) {
+            coordinates.push((location.latitude, location.longitude));
+            row.insert("latitude", location.latitude.to_string());
+            row.insert("longitude", location.longitude.to_string());
+            if let Some(uncertainty) = location.coordinate_uncertainty {
+                row.insert("coordinateUncertainty", uncertainty.to_string());
+            }
+            if let Some(name) = &location.location_name {
+                row.insert("locationName", name.clone());
+            }
+        }
+        deployments.push(row);
+    }
+
+    let all_times: Vec<_> = deployment_times.values().flatten().collect();
+    let temporal = match (all_times.iter().min(), all_times.iter().max()) {
+        (Some(start), Some(end)) => json!({
+            "start": start.format("%Y-%m-%d").to_string(),
+            "end": end.format("%Y-%m-%d").to_string(),
+        }),
+        _ => serde_json::Value::Null,
+    };
+    let spatial = if coordinates.is_empty() {
+        serde_json::Value::Null
+    } else {
+        let fold = |f: fn(f64, f64) -> f64, init: f64, pick: fn(&(f64, f64)) -> f64| {
+            coordinates.iter().map(pick).fold(init, f)
+        };
+        let (min_lat, max_lat) = (
+            fold(f64::min, 90.0, |c| c.0),
+            fold(f64::max, -90.0, |c| c.0),
+        );
+        let (min_lon, max_lon) = (
+            fold(f64::min, 180.0, |c| c.1),
+            fold(f64::max, -180.0, |c| c.1),
+        );
+        json!({
+            "type": "Polygon",
+            "bbox": [min_lon, min_lat, max_lon, max_lat],
+            "coordinates": [[
+                [min_lon, min_lat],
+                [max_lon, min_lat],
+                [max_lon, max_lat],
+                [min_lon, max_lat],
+                [min_lon, min_lat],
+            ]],
+        })
+    };
+
+    let profile_url = |file: &str| {
+        format!(
+            "https://raw.githubusercontent.com/tdwg/camtrap-dp/{}/{}",
+            CAMTRAP_DP_VERSION, file
+        )
+    };
+    let title = if options.title.is_empty() {
+        root_name.clone()
+    } else {
+        options.title.clone()
+    };
+    let mut datapackage = json!({
+        "profile": profile_url("camtrap-dp-profile.json"),
+        "name": title
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>(),
+        "id": uuid::Uuid::new_v4().to_string(),
+        "created": metadata.info.end_time.clone().unwrap_or_else(|| metadata.info.start_time.clone()),
+        "title": title.clone(),
+        "contributors": options.contributors,
+        "project": {
+            "title": title,
+            "samplingDesign": options.sampling_design,
+            "captureMethod": ["activityDetection"],
+            "individualAnimals": false,
+            "observationLevel": ["media"],
+        },
+        "temporal": temporal,
+        "taxonomic": taxa
+            .iter()
+            .map(|name| json!({ "scientificName": name }))
+            .collect::<Vec<_>>(),
+        "resources": TABLES
+            .iter()
+            .map(|(name, file, _)| json!({
+                "name": name,
+                "path": file,
+                "profile": "tabular-data-resource",
+                "format": "csv",
+                "mediatype": "text/csv",
+                "encoding": "utf-8",
+                "schema": profile_url(&format!("{}-table-schema.json", name)),
+            }))
+            .collect::<Vec<_>>(),
+    });
+    if !spatial.is_null() {
+        datapackage["spatial"] = spatial;
+    }
+
+    CamtrapDp {
+        datapackage,
+        deployments,
+        media,
+        observations,
+    }
+}
+
+fn write_table(path: &Path, fields: &[Field], rows: &[Row]) -> Result<(), ExportError> {
+    let mut wtr = csv::Writer::from_path(path)?;
+    wtr.write_record(fields.iter().map(|field| field.name))?;
+    for row in rows {
+        wtr.write_record(
+            fields
+                .iter()
+                .map(|field| row.get(field.name).map(String::as_str).unwrap_or_default()),
+        )?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+impl CamtrapDp {
+    /// Write the package into `dir`, replacing an earlier one.
+    pub fn write(&self, dir: &Path) -> Result<(), ExportError> {
+        std::fs::create_dir_all(dir)?;
+        let tables = [&self.deployments, &self.media, &self.observations];
+        for ((_, file, fields), rows) in TABLES.iter().zip(tables) {
+            write_table(&dir.join(file), fields, rows)?;
+        }
+        let json = serde_json::to_string_pretty(&self.datapackage)?;
+        std::fs::write(dir.join("datapackage.json"), json)?;
+        Ok(())
+    }
+}
+
+/// Write the Camtrap DP package of a run into `camtrap-dp/` in the media folder
+/// and log what the validator finds, e.g. deployments without coordinates.
+pub fn write_camtrap_dp(
+    export_data: &[ExportFrame],
+    metadata: &RunMetadata,
+    options: &CamtrapDpOptions,
+    folder_path: &Path,
+) -> Result<(), ExportError> {
+    let dir = folder_path.join(CAMTRAP_DP_DIR);
+    camtrap_dp(export_data, metadata, options, folder_path).write(&dir)?;
+    match validate(&dir) {
+        Ok(issues) => {
+            for issue in issues.iter().take(20) {
+                log::warn!("Camtrap DP: {}", issue);
+            }
+            if issues.len() > 20 {
+                log::warn!("Camtrap DP: {} more issues", issues.len() - 20);
+            }
+        }
+        Err(e) => log::warn!("Failed to validate Camtrap DP package: {:#}", e),
+    }
+    Ok(())
+}
+
+/// Something in a data package that doesn't conform to Camtrap DP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationIssue {
+    /// `datapackage.json` or a table file
+    pub resource: String,
+    /// 1-based data row, `None` for the descriptor and headers
+    pub row: Option<usize>,
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.resource)?;
+        if let Some(row) = self.row {
+            write!(f, " row {}", row)?;
+        }
+        if let Some(field) = &self.field {
+            write!(f, " {}", field)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+fn check_value(field: &Field, value: &str) -> Option<String> {
+    if value.is_empty() {
+        return field.required.then(|| "is required".to_string());
+    }
+    let number = match field.kind {
+        FieldType::String => None,
+        FieldType::Number => match value.parse::<f64>() {
+            Ok(number) => Some(number),
+            Err(_) => return Some(format!("{:?} is not a number", value)),
+        },
+        FieldType::Integer => match value.parse::<i64>() {
+            Ok(number) => Some(number as f64),
+            Err(_) => return Some(format!("{:?} is not an integer", value)),
+        },
+        FieldType::Boolean => {
+            if value != "true" && value != "false" {
+                return Some(format!("{:?} is not true or false", value));
+            }
+            None
+        }
+        FieldType::Datetime => {
+            if DateTime::parse_from_rfc3339(value).is_err() {
+                return Some(format!(
+                    "{:?} is not an ISO 8601 datetime with timezone",
+                    value
+                ));
+            }
+            None
+        }
+    };
+    match field.constraint {
+        Constraint::None => None,
+        Constraint::Enum(values) => {
+            (!values.contains(&value)).then(|| format!("{:?} is not one of {:?}", value, values))
+        }
+        Constraint::Range(min, max) => number
+            .filter(|number| !(min..=max).contains(number))
+            .map(|number| format!("{} is out of range [{}, {}]", number, min, max)),
+        Constraint::MediaType => {
+            let valid = ["image/", "video/", "audio/"]
+                .iter()
+                .any(|prefix| value.starts_with(prefix));
+            (!valid).then(|| format!("{:?} is not an image, video or audio type", value))
+        }
+    }
+}
+
+/// Check a data package against the Camtrap DP profile and table schemas
+/// without network access: required descriptor properties, table headers,
+/// field types and constraints, unique IDs and references between tables.
+pub fn validate(dir: &Path) -> Result<Vec<ValidationIssue>> {
+    let mut issues = Vec::new();
+    let mut issue = |resource: &str, row: Option<usize>, field: Option<&str>, message: String| {
+        issues.push(ValidationIssue {
+            resource: resource.to_string(),
+            row,
+            field: field.map(str::to_string),
+            message,
+        })
+    };
+
+    let descriptor: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("datapackage.json"))?)?;
+    for property in [
+        "profile",
+        "created",
+        "contributors",
+        "project",
+        "spatial",
+        "temporal",
+        "taxonomic",
+        "resources",
+    ] {
+        if descriptor[property].is_null() {
+            issue(
+                "datapackage.json",
+                None,
+                Some(property),
+                "is required".to_string(),
+            );
+        }
+    }
+    match descriptor["contributors"].as_array() {
+        Some(contributors) if contributors.is_empty() => issue(
+            "datapackage.json",
+            None,
+            Some("contributors"),
+            "needs at least one contributor".to_string(),
+        ),
+        Some(contributors)
+            if contributors.iter().any(|contributor| {
+                contributor["title"].as_str().unwrap_or_default().is_empty()
+            }) =>
+        {
+            issue(
+                "datapackage.json",
+                None,
+                Some("contributors"),
+                "every contributor needs a title".to_string(),
+            )
+        }
+        _ => {}
+    }
+    let project = &descriptor["project"];
+    if project["title"].as_str().unwrap_or_default().is_empty() {
+        issue(
+            "datapackage.json",
+            None,
+            Some("project.title"),
+            "is required".to_string(),
+        );
+    }
+    let sampling_design = project["samplingDesign"].as_str().unwrap_or_default();
+    if !SAMPLING_DESIGNS.contains(&sampling_design) {
+        issue(
+            "datapackage.json",
+            None,
+            Some("project.samplingDesign"),
+            format!("{:?} is not one of {:?}", sampling_design, SAMPLING_DESIGNS),
+        );
+    }
+    if DateTime::parse_from_rfc3339(descriptor["created"].as_str().unwrap_or_default()).is_err() {
+        issue(
+            "datapackage.json",
+            None,
+            Some("created"),
+            "is not an ISO 8601 datetime".to_string(),
+        );
+    }
+    let resources: HashSet<&str> = descriptor["resources"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|resource| resource["name"].as_str())
+        .collect();
+
+    let mut ids: HashMap<&str, HashSet<String>> = HashMap::new();
+    let mut references = Vec::new();
+    for (name, file, fields) in TABLES {
+        if !resources.contains(name) {
+            issue(
+                "datapackage.json",
+                None,
+                Some("resources"),
+                format!("{} resource is missing", name),
+            );
+        }
+        let path = dir.join(file);
+        if !path.is_file() {
+            issue(file, None, None, "file is missing".to_string());
+            continue;
+        }
+        let mut rdr = csv::Reader::from_path(&path)?;
+        let headers = rdr.headers()?.clone();
+        let header_names: Vec<&str> = headers.iter().collect();
+        let expected: Vec<&str> = fields.iter().map(|field| field.name).collect();
+        if header_names != expected {
+            issue(
+                file,
+                None,
+                None,
+                format!("header should be {}", expected.join(",")),
+            );
+        }
+        let columns: Vec<Option<usize>> = fields
+            .iter()
+            .map(|field| headers.iter().position(|header| header == field.name))
+            .collect();
+        let primary_key = fields[0].name;
+        let table_ids = ids.entry(name).or_default();
+        for (i, record) in rdr.records().enumerate() {
+            let record = record?;
+            let row = Some(i + 1);
+            for (field, column) in fields.iter().zip(&columns) {
+                let value = column.and_then(|c| record.get(c)).unwrap_or_default();
+                if let Some(message) = check_value(field, value) {
+                    issue(file, row, Some(field.name), message);
+                }
+                if field.name == primary_key && !value.is_empty() {
+                    if !table_ids.insert(value.to_string()) {
+                        issue(
+                            file,
+                            row,
+                            Some(field.name),
+                            format!("{:?} is not unique", value),
+                        );
+                    }
+                } else if matches!(field.name, "deploymentID" | "mediaID") && !value.is_empty() {
+                    references.push((file, row, field.name, value.to_string()));
+                }
+            }
+        }
+    }
+
+    for (file, row, field, value) in references {
+        let table = if field == "deploymentID" {
+            "deployments"
+        } else {
+            "media"
+        };
+        if !ids.get(table).is_some_and(|ids| ids.contains(&value)) {
+            issue(
+                file,
+                row,
+                Some(field),
+                format!("{:?} is not in {}", value, table),
+            );
+        }
+    }
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tests::{test_frame, test_metadata, TempDir};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_camtrap_dp_validates() {
+        let mut still = test_frame();
+        still.file.file_path = PathBuf::from("/data/site1/cam1/a.jpg");
+        still.video_fps = None;
+        still.timestamp = None;
+        still.video_duration = None;
+        still.sequence_id = Some("0-1".to_string());
+        let mut video = test_frame();
+        video.file.file_path = PathBuf::from("/data/site1/cam1/b.mp4");
+        video.sequence_id = Some("0-1".to_string());
+        let export_data = vec![still, video];
+
+        let tmp = TempDir::new("camtrap");
+        let dir = tmp.path();
+        let mut options = CamtrapDpOptions::default();
+        camtrap_dp(&export_data, &test_metadata(), &options, Path::new("/data"))
+            .write(dir)
+            .unwrap();
+        let issues = validate(dir).unwrap();
+        let fields: HashSet<&str> = issues
+            .iter()
+            .filter_map(|issue| issue.field.as_deref())
+            .collect();
+        // Only what can't be known from the media is missing
+        assert_eq!(
+            fields,
+            HashSet::from(["spatial", "contributors", "latitude", "longitude"])
+        );
+
+        options.contributors.push(Contributor {
+            title: "Field team".to_string(),
+            email: None,
+            role: None,
+        });
+        options.deployments.insert(
+            "site1/cam1".to_string(),
+            DeploymentLocation {
+                latitude: 30.5,
+                longitude: 114.3,
+                coordinate_uncertainty: Some(10),
+                location_name: None,
+            },
+        );
+        let package = camtrap_dp(&export_data, &test_metadata(), &options, Path::new("/data"));
+        package.write(dir).unwrap();
+        let issues = validate(dir).unwrap();
+        assert!(issues.is_empty(), "{:?}", issues);
+        assert_eq!(package.deployments[0]["locationID"], "site1");
+        assert_eq!(package.observations.len(), 2);
+        // The video starts 3 s before its frame at 12:00:03
+        assert_eq!(package.media[1]["timestamp"], "2024-05-01T12:00:00+08:00");
+        // The still's event spans the video of its sequence
+        assert_eq!(
+            package.observations[0]["eventStart"],
+            "2024-05-01T12:00:00+08:00"
+        );
+        assert_eq!(
+            package.observations[0]["eventEnd"],
+            "2024-05-01T12:00:10+08:00"
+        );
+    }
+}
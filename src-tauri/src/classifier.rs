@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage};
+use ndarray::{s, Array4, Axis};
+use ort::{inputs, ExecutionProviderDispatch, Session};
+use serde::{Deserialize, Serialize};
+
+use crate::adapter::{normalize_into, Preprocess};
+use crate::detect::{load_model, DetectError};
+use crate::media::Frame;
+use crate::utils::{Bbox, SpeciesScore};
+
+/// Species classifier run on detection crops, loaded from its own toml.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClassifierConfig {
+    pub name: String,
+    pub path: PathBuf,
+    pub imgsz: usize,
+    /// Class names in the order of the model's output
+    pub classes: Vec<String>,
+    /// Number of species kept per bbox
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Detection labels that get classified
+    #[serde(default = "default_crop_labels")]
+    pub crop_labels: Vec<String>,
+    /// Input and output names, the model's first ones when missing
+    pub input: Option<String>,
+    pub output: Option<String>,
+    /// Apply softmax to the output, disable for models that already output probabilities
+    #[serde(default = "default_softmax")]
+    pub softmax: bool,
+    #[serde(default)]
+    pub preprocess: Preprocess,
+    pub url: Option<String>,
+    pub md5: Option<String>,
+}
+
+fn default_top_k() -> usize {
+    3
+}
+
+fn default_crop_labels() -> Vec<String> {
+    vec!["Animal".to_string()]
+}
+
+fn default_softmax() -> bool {
+    true
+}
+
+/// Paths in the toml are relative to the folder above it, like model configs.
+pub fn load_classifier_config<P: AsRef<Path>>(config: P) -> Result<ClassifierConfig> {
+    let toml_path = PathBuf::from(config.as_ref());
+    let base_dir = toml_path
+        .parent()
+        .and_then(|dir| dir.parent())
+        .unwrap_or(Path::new("."));
+    let toml_str = std::fs::read_to_string(&toml_path)?;
+    let mut classifier_config: ClassifierConfig = toml::from_str(&toml_str)?;
+    classifier_config.path = base_dir.join(&classifier_config.path);
+    Ok(classifier_config)
+}
+
+pub struct Classifier {
+    session: Session,
+    config: ClassifierConfig,
+    input: String,
+    output: String,
+}
+
+impl Classifier {
+    pub fn new(
+        config: ClassifierConfig,
+        ep: ExecutionProviderDispatch,
+    ) -> Result<Self, DetectError> {
+        let session = load_model(&config.path, ep)?;
+        let mismatch = |e: String| DetectError::ModelMismatch(config.path.clone(), e);
+        let input = match &config.input {
+            Some(input) => input.clone(),
+            None => session
+                .inputs
+                .first()
+                .map(|i| i.name.clone())
+                .ok_or_else(|| mismatch("model has no inputs".to_string()))?,
+        };
+        let output = match &config.output {
+            Some(output) => output.clone(),
+            None => session
+                .outputs
+                .first()
+                .map(|o| o.name.clone())
+                .ok_or_else(|| mismatch("model has no outputs".to_string()))?,
+        };
+        if !session.inputs.iter().any(|i| i.name == input) {
+            return Err(mismatch(format!("no input named {:?}", input)));
+        }
+        if !session.outputs.iter().any(|o| o.name == output) {
+            return Err(mismatch(format!("no output named {:?}", output)));
+        }
+        Ok(Self {
+            session,
+            config,
+            input,
+            output,
+        })
+    }
+
+    /// Classify the crops of `crop_labels` boxes and store the top-k species on them.
+    pub fn classify(
+        &self,
+        frames: &[Frame],
+        boxes: &mut [Vec<Bbox>],
+        class_map: &HashMap<usize, String>,
+    ) -> Result<(), DetectError> {
+        let imgsz = self.config.imgsz;
+        let mut targets = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            for (j, bbox) in boxes[i].iter().enumerate() {
+                let crop_label = class_map
+                    .get(&bbox.class)
+                    .is_some_and(|label| self.config.crop_labels.contains(label));
+                if crop_label && bbox.x2 - bbox.x1 >= 1.0 && bbox.y2 - bbox.y1 >= 1.0 {
+                    targets.push((i, j, crop(frame, bbox, imgsz)));
+                }
+            }
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let mut input = Array4::<f32>::zeros((targets.len(), 3, imgsz, imgsz));
+        for (k, (_, _, crop)) in targets.iter().enumerate() {
+            let crop = image_to_array(crop);
+            normalize_into(
+                input.slice_mut(s![k, .., .., ..]),
+                crop.view(),
+                &self.config.preprocess,
+            );
+        }
+
+        let inference_error = |e: ort::Error| DetectError::Inference(e.to_string());
+        let outputs = self
+            .session
+            .run(inputs![self.input.as_str() => input.view()].map_err(inference_error)?)
+            .map_err(inference_error)?;
+        let scores = outputs
+            .get(self.output.as_str())
+            .ok_or_else(|| DetectError::InvalidOutput(format!("missing {}", self.output)))?
+            .try_extract_tensor::<f32>()
+            .map_err(|e| DetectError::InvalidOutput(e.to_string()))?;
+        if scores.ndim() != 2
+            || scores.shape()[0] != targets.len()
+            || scores.shape()[1] != self.config.classes.len()
+        {
+            return Err(DetectError::InvalidOutput(format!(
+                "classifier output shape {:?}, expected [{}, {}]",
+                scores.shape(),
+                targets.len(),
+                self.config.classes.len()
+            )));
+        }
+
+        for ((i, j, _), row) in targets.iter().zip(scores.axis_iter(Axis(0))) {
+            let mut row: Vec<f32> = row.iter().copied().collect();
+            if self.config.softmax {
+                softmax(&mut row);
+            }
+            let mut ranked: Vec<(usize, f32)> = row.into_iter().enumerate().collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            boxes[*i][*j].species = Some(
+                ranked
+                    .into_iter()
+                    .take(self.config.top_k)
+                    .map(|(class, score)| SpeciesScore {
+                        label: self.config.classes[class].clone(),
+                        score,
+                    })
+                    .collect(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Crop a bbox and resize it to the classifier input. Stills are cropped from
+/// the full resolution image, video frames from the letterboxed detector input.
+fn crop(frame: &Frame, bbox: &Bbox, imgsz: usize) -> RgbImage {
+    let region = match &frame.original {
+        Some(original) => {
+            let x = (bbox.x1 as u32).min(original.width().saturating_sub(1));
+            let y = (bbox.y1 as u32).min(original.height().saturating_sub(1));
+            let width = ((bbox.x2 - bbox.x1) as u32).clamp(1, original.width() - x);
+            let height = ((bbox.y2 - bbox.y1) as u32).clamp(1, original.height() - y);
+            imageops::crop_imm(original, x, y, width, height).to_image()
+        }
+        None => {
+            // Back from original to letterboxed coordinates
+            let (_, size_h, size_w) = frame.data.dim();
+            let to_input =
+                |v: f32, pad: usize, size: usize| ((v / frame.ratio) as usize + pad).min(size - 1);
+            let x1 = to_input(bbox.x1, frame.padding.0, size_w);
+            let y1 = to_input(bbox.y1, frame.padding.1, size_h);
+            let x2 = to_input(bbox.x2, frame.padding.0, size_w).max(x1 + 1);
+            let y2 = to_input(bbox.y2, frame.padding.1, size_h).max(y1 + 1);
+            RgbImage::from_fn((x2 - x1) as u32, (y2 - y1) as u32, |x, y| {
+                let (x, y) = (x1 + x as usize, y1 + y as usize);
+                Rgb([0, 1, 2].map(|c| (frame.data[[c, y, x]] * 255.0).round() as u8))
+            })
+        }
+    };
+    imageops::resize(&region, imgsz as u32, imgsz as u32, FilterType::Triangle)
+}
+
+/// `[3, H, W]` 0–1 RGB array of an image.
+fn image_to_array(image: &RgbImage) -> ndarray::Array3<f32> {
+    let (width, height) = image.dimensions();
+    ndarray::Array3::from_shape_fn((3, height as usize, width as usize), |(c, y, x)| {
+        image.get_pixel(x as u32, y as u32)[c] as f32 / 255.0
+    })
+}
+
+fn softmax(values: &mut [f32]) {
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for value in values.iter_mut() {
+        *value = (*value - max).exp();
+        sum += *value;
+    }
+    for value in values.iter_mut() {
+        *value /= sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_softmax() {
+        let mut values = vec![1.0, 2.0, 3.0];
+        softmax(&mut values);
+        assert!((values.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(values[2] > values[1] && values[1] > values[0]);
+    }
+}
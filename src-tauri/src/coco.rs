@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::adapter::Architecture;
+use crate::export::{
+    parse_shoot_time, ExportError, ExportFrame, ModelInfo, RunConfig, RunInfo, RunMetadata,
+    RESULT_FORMAT_VERSION,
+};
+use crate::media::{MediaExtensions, VideoSampling};
+use crate::megadetector::relative_path;
+use crate::sequence::sequences;
+use crate::utils::{Bbox, FileItem};
+
+/// Category of images without detections, as in LILA COCO Camera Traps datasets.
+const EMPTY_CATEGORY: &str = "empty";
+
+/// COCO Camera Traps dataset. Each sampled video frame is an image, a video
+/// is one sequence.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CocoCameraTraps {
+    pub info: CocoInfo,
+    pub images: Vec<CocoImage>,
+    pub annotations: Vec<CocoAnnotation>,
+    pub categories: Vec<CocoCategory>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CocoInfo {
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    pub date_created: Option<String>,
+    /// Run that produced the file, missing in datasets from elsewhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run: Option<RunMetadata>,
+}
+
+/// Ids are strings or integers depending on who wrote the dataset.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CocoId {
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CocoImage {
+    pub id: CocoId,
+    /// Path relative to the media folder, with forward slashes
+    pub file_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<usize>,
+    /// Local shoot time as `YYYY-MM-DD HH:MM:SS`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub datetime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq_num_frames: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_num: Option<usize>,
+    /// Folder of the file, one camera per folder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    /// Not part of COCO Camera Traps. Frame of a video as in `result.json`,
+    /// `frame_num` counts within the sequence instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_index: Option<usize>,
+    /// Not part of COCO Camera Traps. Seconds from the start of the video
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_fps: Option<f64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub iframe: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CocoAnnotation {
+    pub id: CocoId,
+    pub image_id: CocoId,
+    pub category_id: CocoId,
+    /// Absolute `[x, y, width, height]`, missing for image level labels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<[f32; 4]>,
+    /// Detection confidence, missing in ground truth
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CocoCategory {
+    pub id: CocoId,
+    pub name: String,
+}
+
+pub fn coco_camera_traps(
+    export_data: &[ExportFrame],
+    metadata: &RunMetadata,
+    folder_path: &Path,
+) -> CocoCameraTraps {
+    // Failed frames have nothing to annotate
+    let frames: Vec<&ExportFrame> = export_data
+        .iter()
+        .filter(|frame| frame.bboxes.is_some())
+        .collect();
+    if frames.len() < export_data.len() {
+        log::warn!(
+            "{} failed frames left out of the COCO export",
+            export_data.len() - frames.len()
+        );
+    }
+
+    // Category 0 is empty, classes follow in model order
+    let mut categories = vec![CocoCategory {
+        id: CocoId::Int(0),
+        name: EMPTY_CATEGORY.to_string(),
+    }];
+    categories.extend(
+        metadata
+            .info
+            .model
+            .classes
+            .iter()
+            .enumerate()
+            .map(|(i, class)| CocoCategory {
+                id: CocoId::Int(i as i64 + 1),
+                name: class.clone(),
+            }),
+    );
+
+    let mut images = Vec::with_capacity(frames.len());
+    let mut annotations = Vec::new();
+    for (frame, (seq_id, frame_num, seq_num_frames)) in
+        frames.iter().zip(sequences(&frames, folder_path, None))
+    {
+        let file_name = relative_path(&frame.file.file_path, folder_path);
+        let is_video = frame.video_fps.is_some() || frame.total_frames > 1;
+        let id = if is_video {
+            CocoId::Str(format!("{}#{}", file_name, frame.frame_index))
+        } else {
+            CocoId::Str(file_name.clone())
+        };
+        let bboxes = frame.bboxes.as_deref().unwrap_or_default();
+        if bboxes.is_empty() {
+            annotations.push(CocoAnnotation {
+                id: CocoId::Int(annotations.len() as i64 + 1),
+                image_id: id.clone(),
+                category_id: CocoId::Int(0),
+                bbox: None,
+                score: None,
+            });
+        }
+        for bbox in bboxes {
+            annotations.push(CocoAnnotation {
+                id: CocoId::Int(annotations.len() as i64 + 1),
+                image_id: id.clone(),
+                category_id: CocoId::Int(bbox.class as i64 + 1),
+                bbox: Some([bbox.x1, bbox.y1, bbox.x2 - bbox.x1, bbox.y2 - bbox.y1]),
+                score: Some(bbox.score),
+            });
+        }
+        images.push(CocoImage {
+            id,
+            width: frame.width,
+            height: frame.height,
+            datetime: frame
+                .shoot_time
+                .as_deref()
+                .and_then(parse_shoot_time)
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string()),
+            seq_id: Some(seq_id),
+            seq_num_frames: Some(seq_num_frames),
+            frame_num: Some(frame_num),
+            location: Path::new(&file_name)
+                .parent()
+                .filter(|folder| !folder.as_os_str().is_empty())
+                .map(|folder| folder.to_string_lossy().into_owned()),
+            file_name,
+            frame_index: is_video.then_some(frame.frame_index),
+            timestamp: frame.timestamp,
+            video_duration: frame.video_duration,
+            video_fps: frame.video_fps,
+            iframe: frame.iframe,
+        });
+    }
+
+    CocoCameraTraps {
+        info: CocoInfo {
+            version: metadata.info.app_version.clone(),
+            description: format!("Detections of {}", metadata.info.model.name),
+            date_created: metadata.info.end_time.clone(),
+            run: Some(metadata.clone()),
+        },
+        images,
+        annotations,
+        categories,
+    }
+}
+
+pub fn write_coco_camera_traps(
+    export_data: &[ExportFrame],
+    metadata: &RunMetadata,
+    folder_path: &Path,
+) -> Result<(), ExportError> {
+    let coco = coco_camera_traps(export_data, metadata, folder_path);
+    let json = serde_json::to_string_pretty(&coco)?;
+    let mut file = File::create(folder_path.join("result.coco.json"))?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+/// Shoot time of a COCO `datetime`. It has no UTC offset, so it is taken as
+/// local time like the EXIF times of detection. Other formats are kept as is.
+fn import_datetime(datetime: &str) -> String {
+    NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S%.f")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(|time| time.to_string())
+        .unwrap_or_else(|| datetime.to_string())
+}
+
+/// Frames of a COCO Camera Traps dataset, with `file_name`s relative to
+/// `folder_path`. Bbox classes index the non-empty categories sorted by id,
+/// which are the model classes for datasets written by [`coco_camera_traps`].
+/// Ground truth without scores gets a score of 1, image level annotations
+/// without a bbox get a box over the whole image.
+pub fn import_coco_camera_traps(
+    coco: &CocoCameraTraps,
+    folder_path: &Path,
+) -> Result<(Vec<ExportFrame>, Vec<String>)> {
+    let mut categories: Vec<&CocoCategory> = coco.categories.iter().collect();
+    categories.sort_by(|a, b| a.id.cmp(&b.id));
+    let is_empty = |name: &str| {
+        name.eq_ignore_ascii_case(EMPTY_CATEGORY) || name.eq_ignore_ascii_case("blank")
+    };
+    let classes: Vec<String> = categories
+        .iter()
+        .filter(|category| !is_empty(&category.name))
+        .map(|category| category.name.clone())
+        .collect();
+    let class_ids: HashMap<&CocoId, Option<usize>> = categories
+        .iter()
+        .map(|category| {
+            let class = classes.iter().position(|class| *class == category.name);
+            (&category.id, class.filter(|_| !is_empty(&category.name)))
+        })
+        .collect();
+
+    let mut image_annotations: HashMap<&CocoId, Vec<&CocoAnnotation>> = HashMap::new();
+    for annotation in &coco.annotations {
+        image_annotations
+            .entry(&annotation.image_id)
+            .or_default()
+            .push(annotation);
+    }
+
+    // Frames of one file, e.g. a video, share its file_name
+    let mut files: BTreeMap<&str, Vec<&CocoImage>> = BTreeMap::new();
+    for image in &coco.images {
+        files
+            .entry(image.file_name.as_str())
+            .or_default()
+            .push(image);
+    }
+
+    let mut folder_ids: HashMap<PathBuf, usize> = HashMap::new();
+    let mut export_data = Vec::with_capacity(coco.images.len());
+    for (file_id, (file_name, mut images)) in files.into_iter().enumerate() {
+        images.sort_by_key(|image| (image.frame_index, image.frame_num));
+        let file_path = folder_path.join(file_name);
+        let folder = file_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let next_folder_id = folder_ids.len();
+        let folder_id = *folder_ids.entry(folder).or_insert(next_folder_id);
+        let total_frames = images.len();
+        for (i, image) in images.into_iter().enumerate() {
+            // frame_num counts within the sequence, frame_index within the file
+            let frame_index = image.frame_index.unwrap_or(i);
+            let mut bboxes = Vec::new();
+            let mut label = HashSet::new();
+            for annotation in image_annotations.get(&image.id).into_iter().flatten() {
+                let class = *class_ids.get(&annotation.category_id).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown category {:?} in {}",
+                        annotation.category_id,
+                        file_name
+                    )
+                })?;
+                let Some(class) = class else {
+                    continue;
+                };
+                label.insert(classes[class].clone());
+                // Without a box organize and reports would see the image as blank
+                let [x, y, width, height] = annotation.bbox.unwrap_or([
+                    0.0,
+                    0.0,
+                    image.width.unwrap_or_default() as f32,
+                    image.height.unwrap_or_default() as f32,
+                ]);
+                bboxes.push(Bbox {
+                    x1: x,
+                    y1: y,
+                    x2: x + width,
+                    y2: y + height,
+                    score: annotation.score.unwrap_or(1.0),
+                    class,
+                    species: None,
+                });
+            }
+            if label.is_empty() {
+                label.insert("Blank".to_string());
+            }
+            export_data.push(ExportFrame {
+                file: FileItem::new(folder_id, file_id, file_path.clone(), None),
+                shoot_time: image.datetime.as_deref().map(import_datetime),
+                shoot_time_source: None,
+                orientation: None,
+                width: image.width,
+                height: image.height,
+                frame_index,
+                total_frames,
+                bboxes: Some(bboxes),
+                label: Some(label),
+                error: None,
+                iframe: image.iframe,
+                timestamp: image.timestamp,
+                video_duration: image.video_duration,
+                video_fps: image.video_fps,
+                sequence_id: image.seq_id.clone(),
+            });
+        }
+    }
+    Ok((export_data, classes))
+}
+
+/// Metadata of a dataset without a recorded run, like ground truth from
+/// elsewhere. Its categories are the classes, so organize and reports don't
+/// take category indices for MegaDetector classes.
+pub fn dataset_metadata(info: &CocoInfo, classes: Vec<String>) -> RunMetadata {
+    let name = match info.description.as_str() {
+        "" => "COCO Camera Traps".to_string(),
+        description => description.to_string(),
+    };
+    RunMetadata {
+        info: RunInfo {
+            format_version: RESULT_FORMAT_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            model: ModelInfo {
+                name,
+                path: PathBuf::new(),
+                classes,
+                md5: None,
+                // Not detected, boxes are in image pixels like YOLO output
+                architecture: Architecture::YoloNms,
+            },
+            classifier: None,
+            ep: Vec::new(),
+            start_time: info.date_created.clone().unwrap_or_default(),
+            end_time: info.date_created.clone(),
+        },
+        config: RunConfig {
+            confidence_threshold: 0.0,
+            iou_threshold: 0.0,
+            class_thresholds: BTreeMap::new(),
+            iframe_only: false,
+            max_frames: None,
+            video_sampling: VideoSampling::default(),
+            extensions: MediaExtensions::default(),
+            tiling: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::tests::{still, test_frame, test_metadata};
+
+    #[test]
+    fn test_coco_round_trip() {
+        let blank = still("/data/cam1/b.jpg", Some("2024-05-01 12:00:30 +08:00"), None);
+        let mut export_data = vec![
+            still(
+                "/data/cam1/a.jpg",
+                Some("2024-05-01 12:00:00 +08:00"),
+                Some(0),
+            ),
+            blank,
+            still(
+                "/data/cam1/c.jpg",
+                Some("2024-05-01 13:00:00 +08:00"),
+                Some(0),
+            ),
+            still(
+                "/data/cam2/d.jpg",
+                Some("2024-05-01 12:00:10 +08:00"),
+                Some(0),
+            ),
+        ];
+        export_data[3].file.folder_id = 2;
+        let mut video = test_frame();
+        video.file.file_path = PathBuf::from("/data/cam2/e.mp4");
+        export_data.push(video);
+        let coco = coco_camera_traps(&export_data, &test_metadata(), Path::new("/data"));
+        assert_eq!(coco.images.len(), 5);
+        assert_eq!(coco.categories[1].name, "Animal");
+        // a and b are 30 s apart, c an hour later
+        let seq: Vec<&str> = coco
+            .images
+            .iter()
+            .map(|image| image.seq_id.as_deref().unwrap())
+            .collect();
+        assert_eq!(seq[0], seq[1]);
+        assert_ne!(seq[1], seq[2]);
+        assert_eq!(coco.images[1].frame_num, Some(1));
+        assert_eq!(coco.images[1].location.as_deref(), Some("cam1"));
+        assert_eq!(
+            coco.images[0].datetime.as_deref(),
+            Some("2024-05-01 12:00:00")
+        );
+
+        let (frames, classes) = import_coco_camera_traps(&coco, Path::new("/data")).unwrap();
+        assert_eq!(classes, vec!["Animal", "Person", "Vehicle"]);
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[0].file.file_path, PathBuf::from("/data/cam1/a.jpg"));
+        let bbox = &frames[0].bboxes.as_ref().unwrap()[0];
+        assert_eq!((bbox.class, bbox.x2, bbox.score), (0, 3.0, 0.5));
+        assert!(frames[1].bboxes.as_ref().unwrap().is_empty());
+        assert!(frames[1].label.as_ref().unwrap().contains("Blank"));
+        let shoot_time = parse_shoot_time(frames[0].shoot_time.as_deref().unwrap()).unwrap();
+        assert_eq!(shoot_time.naive_local().to_string(), "2024-05-01 12:00:00");
+        // Video frames keep their place in the video
+        let video = &frames[4];
+        assert_eq!((video.frame_index, video.timestamp), (3, Some(3.0)));
+        assert_eq!((video.video_fps, video.iframe), (Some(29.97), true));
+        assert_eq!(frames[0].frame_index, 0);
+    }
+
+    #[test]
+    fn test_import_ground_truth() {
+        let coco: CocoCameraTraps = serde_json::from_value(serde_json::json!({
+            "info": {"description": "Survey"},
+            "images": [{"id": 1, "file_name": "cam1/a.jpg", "width": 4, "height": 3}],
+            "annotations": [{"id": 1, "image_id": 1, "category_id": 5}],
+            "categories": [{"id": 0, "name": "empty"}, {"id": 5, "name": "Sus scrofa"}]
+        }))
+        .unwrap();
+        let (frames, classes) = import_coco_camera_traps(&coco, Path::new("/data")).unwrap();
+        // An image level label is a box over the whole image
+        let bbox = &frames[0].bboxes.as_ref().unwrap()[0];
+        assert_eq!((bbox.class, bbox.x2, bbox.y2), (0, 4.0, 3.0));
+        let metadata = dataset_metadata(&coco.info, classes);
+        assert_eq!(metadata.info.model.classes, vec!["Sus scrofa"]);
+        assert_eq!(metadata.info.model.name, "Survey");
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Shared cancel/pause state of a detection job.
+///
+/// Media workers check it before picking up a new file, so a cancelled or
+/// paused job stops feeding the detectors while in-flight batches drain.
+#[derive(Debug, Default)]
+pub struct JobControl {
+    cancelled: AtomicBool,
+    paused: Mutex<bool>,
+    resumed: Condvar,
+    fatal_error: Mutex<Option<String>>,
+}
+
+impl JobControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Clear cancel and pause flags before a new job starts.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+        *self.fatal_error.lock().unwrap() = None;
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        // Wake paused workers so they can observe the cancel flag
+        let _paused = self.paused.lock().unwrap();
+        self.resumed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Abort the job because of an unrecoverable error. Only the first error is kept.
+    pub fn fail(&self, error: String) {
+        log::error!("{}", error);
+        self.fatal_error.lock().unwrap().get_or_insert(error);
+        self.cancel();
+    }
+
+    pub fn fatal_error(&self) -> Option<String> {
+        self.fatal_error.lock().unwrap().clone()
+    }
+
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Block while the job is paused. Returns `false` if the job was cancelled
+    /// and no new work should be started.
+    pub fn proceed(&self) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused && !self.is_cancelled() {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+        !self.is_cancelled()
+    }
+}
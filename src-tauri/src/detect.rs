@@ -1,18 +1,45 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
 use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
-use ndarray::{array, s, Array2, Array4, Axis};
-use ort::{inputs, ExecutionProviderDispatch, Session, SessionOutputs};
+use ort::{ExecutionProviderDispatch, Session};
+use thiserror::Error;
 
+use crate::adapter::{
+    model_adapter, validate_session, Architecture, ModelAdapter, ModelInput, Preprocess,
+    TensorConfig,
+};
+use crate::classifier::{Classifier, ClassifierConfig};
+use crate::control::JobControl;
 use crate::export::ExportFrame;
 use crate::media::{ArrayItem, Frame};
+use crate::tiling::{make_tiles, Tile, Tiling};
 use crate::utils::{nms, Bbox, Ep};
 
+#[derive(Error, Debug)]
+pub enum DetectError {
+    #[error("Failed to load model {0}: {1}")]
+    ModelLoad(PathBuf, String),
+
+    #[error("Model {0} does not match its config: {1}")]
+    ModelMismatch(PathBuf, String),
+
+    #[error("Inference failed: {0}")]
+    Inference(String),
+
+    #[error("Invalid model output: {0}")]
+    InvalidOutput(String),
+
+    #[error("Class ID {0} not found in model classes")]
+    UnknownClass(usize),
+
+    #[error("Export queue closed")]
+    ExportClosed,
+}
+
 #[derive(Clone, Debug)]
 pub struct DetectConfig {
     pub ep: Ep,
@@ -20,88 +47,191 @@ pub struct DetectConfig {
     pub model_path: PathBuf,
     pub target_size: usize,
     pub class_map: HashMap<usize, String>,
+    /// Threshold of classes missing from `class_thresholds`
     pub conf_thres: f32,
+    /// Effective threshold per class id, see `class_thresholds`
+    pub class_thresholds: HashMap<usize, f32>,
     pub iou_thres: f32,
     pub batch_size: usize,
     pub timeout: usize,
     pub model_name: String,
+    pub architecture: Architecture,
+    pub tensors: TensorConfig,
+    pub preprocess: Preprocess,
+    pub classifier: Option<ClassifierConfig>,
+    /// Also detect on full resolution tiles of stills
+    pub tiling: Option<Tiling>,
 }
 
+impl DetectConfig {
+    /// Whether a box of `class` with `score` is kept. Classes filtered out by the
+    /// include/exclude lists are never kept, ids outside the model's classes
+    /// use `conf_thres` so they still surface as `UnknownClass`.
+    pub fn accepts(&self, class: usize, score: f32) -> bool {
+        match self.class_thresholds.get(&class) {
+            Some(threshold) => score >= *threshold,
+            None => !self.class_map.contains_key(&class) && score >= self.conf_thres,
+        }
+    }
+
+    /// Lowest threshold of any kept class, for filtering before the class is known.
+    pub fn min_conf_thres(&self) -> f32 {
+        self.class_thresholds
+            .values()
+            .copied()
+            .fold(self.conf_thres, f32::min)
+    }
+}
+
+/// Resolve per-class thresholds and class filters by name into thresholds by
+/// class id. Excluded classes, or classes not included when `include` isn't
+/// empty, are left out.
+pub fn class_thresholds(
+    class_map: &HashMap<usize, String>,
+    conf_thres: f32,
+    per_class: &BTreeMap<String, f32>,
+    include: &[String],
+    exclude: &[String],
+) -> Result<HashMap<usize, f32>, String> {
+    let known: HashSet<&String> = class_map.values().collect();
+    for name in per_class.keys().chain(include).chain(exclude) {
+        if !known.contains(name) {
+            return Err(format!(
+                "Unknown class {:?}, model classes are {:?}",
+                name,
+                class_map.values().collect::<BTreeSet<_>>()
+            ));
+        }
+    }
+    Ok(class_map
+        .iter()
+        .filter(|(_, name)| include.is_empty() || include.contains(name))
+        .filter(|(_, name)| !exclude.contains(name))
+        .map(|(&id, name)| (id, per_class.get(name).copied().unwrap_or(conf_thres)))
+        .collect())
+}
+
+/// Spawn a detect worker. A fatal error (e.g. the model fails to load) is
+/// reported to `control`, which stops the job, and returned from the thread.
 pub fn detect_worker(
     config: Arc<DetectConfig>,
     array_q_recv: Receiver<ArrayItem>,
     export_q_s: Sender<ExportFrame>,
-) -> thread::JoinHandle<()> {
+    control: Arc<JobControl>,
+) -> thread::JoinHandle<Result<(), DetectError>> {
     thread::spawn(move || {
-        let ep;
-        let model_dir = config.model_path.parent().unwrap().to_str().unwrap();
-        match config.ep {
-            Ep::CoreML => {
-                log::info!("Using CoreML EP");
-                ep = ort::CoreMLExecutionProvider::default()
-                    .with_ane_only()
-                    .with_subgraphs()
-                    .build();
-            }
-            Ep::TensorRT => {
-                log::info!("Using TensorRT EP on device {}", config.device);
-                ep = ort::TensorRTExecutionProvider::default()
-                    .with_engine_cache(true)
-                    .with_engine_cache_path(&model_dir)
-                    .with_timing_cache(true)
-                    .with_fp16(true)
-                    .with_profile_min_shapes(format!(
-                        "images:1x3x{}x{}",
-                        config.target_size, config.target_size
-                    ))
-                    .with_profile_opt_shapes(format!(
-                        "images:2x3x{}x{}",
-                        config.target_size, config.target_size
-                    ))
-                    .with_profile_max_shapes(format!(
-                        "images:5x3x{}x{}",
-                        config.target_size, config.target_size
-                    ))
-                    .with_device_id(config.device.parse().unwrap_or(0))
-                    .build();
-            }
-            Ep::CUDA => {
-                log::info!("Using CUDA EP on device {}", config.device);
-                ep = ort::CUDAExecutionProvider::default()
-                    .with_device_id(config.device.parse().unwrap_or(0))
-                    .build();
-            }
-            Ep::OpenVINO => {
-                let device_type = config.device.to_uppercase();
-
-                log::info!("Using OpenVINO EP with device type: {}", device_type);
-                ep = ort::OpenVINOExecutionProvider::default()
-                    .with_device_type(device_type)
-                    .with_cache_dir(&model_dir)
-                    .build();
-            }
-            Ep::DirectML => {
-                log::info!("Using DirectML EP on device {}", config.device);
-                ep = ort::DirectMLExecutionProvider::default()
-                    .with_device_id(config.device.parse().unwrap_or(0))
-                    .build();
-            }
-            Ep::Cpu => {
-                log::info!("Using CPU EP");
-                ep = ort::CPUExecutionProvider::default().build();
-            }
+        let result = run_detect_worker(&config, array_q_recv, export_q_s);
+        if let Err(e) = &result {
+            control.fail(e.to_string());
+        }
+        result
+    })
+}
+
+fn run_detect_worker(
+    config: &DetectConfig,
+    array_q_recv: Receiver<ArrayItem>,
+    export_q_s: Sender<ExportFrame>,
+) -> Result<(), DetectError> {
+    let ep;
+    let model_dir = config
+        .model_path
+        .parent()
+        .and_then(|dir| dir.to_str())
+        .unwrap_or(".");
+    match config.ep {
+        Ep::CoreML => {
+            log::info!("Using CoreML EP");
+            ep = ort::CoreMLExecutionProvider::default()
+                .with_ane_only()
+                .with_subgraphs()
+                .build();
+        }
+        Ep::TensorRT => {
+            log::info!("Using TensorRT EP on device {}", config.device);
+            ep = ort::TensorRTExecutionProvider::default()
+                .with_engine_cache(true)
+                .with_engine_cache_path(&model_dir)
+                .with_timing_cache(true)
+                .with_fp16(true)
+                .with_profile_min_shapes(format!(
+                    "{}:1x3x{}x{}",
+                    config.tensors.input, config.target_size, config.target_size
+                ))
+                .with_profile_opt_shapes(format!(
+                    "{}:2x3x{}x{}",
+                    config.tensors.input, config.target_size, config.target_size
+                ))
+                .with_profile_max_shapes(format!(
+                    "{}:5x3x{}x{}",
+                    config.tensors.input, config.target_size, config.target_size
+                ))
+                .with_device_id(config.device.parse().unwrap_or(0))
+                .build();
         }
+        Ep::CUDA => {
+            log::info!("Using CUDA EP on device {}", config.device);
+            ep = ort::CUDAExecutionProvider::default()
+                .with_device_id(config.device.parse().unwrap_or(0))
+                .build();
+        }
+        Ep::OpenVINO => {
+            let device_type = config.device.to_uppercase();
 
-        let session = load_model(&config.model_path, ep).unwrap();
+            log::info!("Using OpenVINO EP with device type: {}", device_type);
+            ep = ort::OpenVINOExecutionProvider::default()
+                .with_device_type(device_type)
+                .with_cache_dir(&model_dir)
+                .build();
+        }
+        Ep::DirectML => {
+            log::info!("Using DirectML EP on device {}", config.device);
+            ep = ort::DirectMLExecutionProvider::default()
+                .with_device_id(config.device.parse().unwrap_or(0))
+                .build();
+        }
+        Ep::Cpu => {
+            log::info!("Using CPU EP");
+            ep = ort::CPUExecutionProvider::default().build();
+        }
+    }
 
-        process_frames(array_q_recv, export_q_s, &session, &config).unwrap();
-    })
+    let classifier = match &config.classifier {
+        Some(classifier_config) => Some(Classifier::new(classifier_config.clone(), ep.clone())?),
+        None => None,
+    };
+    let session = load_model(&config.model_path, ep)?;
+    validate_session(
+        &session,
+        config.architecture,
+        &config.tensors,
+        config.target_size,
+    )
+    .map_err(|e| DetectError::ModelMismatch(config.model_path.clone(), e))?;
+    let adapter = model_adapter(config.architecture);
+
+    process_frames(
+        array_q_recv,
+        export_q_s,
+        &session,
+        adapter.as_ref(),
+        classifier.as_ref(),
+        config,
+    )
 }
 
-pub fn load_model(model_path: &Path, ep: ExecutionProviderDispatch) -> Result<Session> {
-    let model = Session::builder()?
-        .with_execution_providers([ep])?
-        .commit_from_file(model_path)?;
+pub fn load_model(
+    model_path: &Path,
+    ep: ExecutionProviderDispatch,
+) -> Result<Session, DetectError> {
+    let model_load_error =
+        |e: ort::Error| DetectError::ModelLoad(model_path.to_path_buf(), e.to_string());
+    let model = Session::builder()
+        .map_err(model_load_error)?
+        .with_execution_providers([ep])
+        .map_err(model_load_error)?
+        .commit_from_file(model_path)
+        .map_err(model_load_error)?;
 
     Ok(model)
 }
@@ -110,8 +240,10 @@ pub fn process_frames(
     rx: Receiver<ArrayItem>,
     s: Sender<ExportFrame>,
     model: &Session,
+    adapter: &dyn ModelAdapter,
+    classifier: Option<&Classifier>,
     config: &DetectConfig,
-) -> Result<()> {
+) -> Result<(), DetectError> {
     let mut frames: Vec<Frame> = Vec::new();
     let mut last_receive_time = Instant::now();
     let timeout = Duration::from_millis(config.timeout as u64);
@@ -120,7 +252,7 @@ pub fn process_frames(
             if !frames.is_empty() {
                 // Process the batch of frames
                 log::debug!("Processing frame number: {}", frames.len());
-                process_batch(&frames, model, config, &s)?;
+                process_batch(&frames, model, adapter, classifier, config, &s)?;
                 frames.clear();
             }
             last_receive_time = Instant::now();
@@ -136,14 +268,22 @@ pub fn process_frames(
                         .send(ExportFrame {
                             file: err_file.file,
                             shoot_time: None,
+                            shoot_time_source: None,
+                            orientation: None,
+                            width: None,
+                            height: None,
                             frame_index: 0,
                             total_frames: 1,
                             bboxes: Some(vec![]),
                             label: None,
-                            error: Some(err_file.error.to_string()),
+                            error: Some(format!("{:#}", err_file.error)),
                             iframe: false,
+                            timestamp: None,
+                            video_duration: None,
+                            video_fps: None,
+                            sequence_id: None,
                         })
-                        .unwrap(),
+                        .map_err(|_| DetectError::ExportClosed)?,
                 }
 
                 last_receive_time = Instant::now();
@@ -155,7 +295,7 @@ pub fn process_frames(
                         "Recieve frame timeout! Processing frame number: {}",
                         frames.len()
                     );
-                    process_batch(&frames, model, config, &s)?;
+                    process_batch(&frames, model, adapter, classifier, config, &s)?;
                     frames.clear();
                 }
                 last_receive_time = Instant::now();
@@ -166,7 +306,7 @@ pub fn process_frames(
                         "Channel disconnected! Processing frame number: {}",
                         frames.len()
                     );
-                    process_batch(&frames, model, config, &s)?;
+                    process_batch(&frames, model, adapter, classifier, config, &s)?;
                     frames.clear();
                 }
                 // Channel disconnected, exit the loop
@@ -177,144 +317,140 @@ pub fn process_frames(
     Ok(())
 }
 
+/// Run one batch and send an `ExportFrame` per input frame. Inference errors
+/// are attached to the frames of the batch instead of stopping the worker.
 pub fn process_batch(
     frames: &[Frame],
     model: &Session,
+    adapter: &dyn ModelAdapter,
+    classifier: Option<&Classifier>,
     config: &DetectConfig,
     export_q_s: &Sender<ExportFrame>,
-) -> Result<()> {
-    let batch_size = frames.len();
-    let mut inputs = Array4::<f32>::zeros((batch_size, 3, config.target_size, config.target_size));
-    let outputs: SessionOutputs;
-    if config.model_name.contains("rtdetr") {
-        let mut orig_target_sizes = Array2::<i64>::zeros((batch_size, 2));
-        for (i, frame) in frames.iter().enumerate() {
-            orig_target_sizes.slice_mut(s![i, ..]).assign(&array![
-                frame.width.max(frame.height) as i64,
-                frame.height.max(frame.width) as i64
-            ]);
-            inputs
-                .slice_mut(s![i, .., ..config.target_size, ..config.target_size])
-                .assign(&frame.data);
-        }
-
-        outputs = model
-            .run(
-                inputs! {
-                    "images" => inputs.view(),
-                    "orig_target_sizes" => orig_target_sizes.view()
-                }
-                .unwrap(),
-            )
-            .unwrap();
-    } else {
-        for (i, frame) in frames.iter().enumerate() {
-            inputs
-                .slice_mut(s![i, .., ..config.target_size, ..config.target_size])
-                .assign(&frame.data);
-        }
-        outputs = model
-            .run(inputs!["images" => inputs.view()].unwrap())
-            .unwrap();
-    }
-
-    let output = outputs["output0"]
-        .try_extract_tensor::<f32>()
-        .unwrap()
-        .t()
-        .into_owned(); //[6, 102000, batch]
-
-    // Iterate batch/frame
-    for i in 0..batch_size {
-        let output = output.slice(s![.., .., i]); //[6, 102000]
-        let mut boxes: Vec<Bbox> = vec![];
-        if config.model_name.contains("rtdetr") {
-            for row in output.axis_iter(Axis(1)) {
-                // output tensor:[x1, y1, x2, y2, prob, class_id]
-                // rtdetr bbox is original size
-                let row: Vec<_> = row.iter().copied().collect();
-                let class_id = row[5] as usize;
-                let prob = row[4];
-                if prob < config.conf_thres {
-                    continue;
+) -> Result<(), DetectError> {
+    let mut classify_error = None;
+    let results: Vec<Result<Vec<Bbox>, String>> =
+        match detect_frames(frames, model, adapter, config) {
+            Ok(mut boxes) => {
+                if let Some(classifier) = classifier {
+                    // Detections are still worth keeping when the classifier fails
+                    if let Err(e) = classifier.classify(frames, &mut boxes, &config.class_map) {
+                        log::error!("Classifier: {} ({} frames affected)", e, frames.len());
+                        classify_error = Some(format!("Classifier: {}", e));
+                    }
                 }
-                let mut x1 = row[0] as f32 - frames[i].padding.0 as f32 * frames[i].ratio;
-                let mut y1 = row[1] as f32 - frames[i].padding.1 as f32 * frames[i].ratio;
-                let mut x2 = row[2] as f32 - frames[i].padding.0 as f32 * frames[i].ratio;
-                let mut y2 = row[3] as f32 - frames[i].padding.1 as f32 * frames[i].ratio;
-                x1 = x1.max(0.0).min(frames[i].width as f32);
-                y1 = y1.max(0.0).min(frames[i].height as f32);
-                x2 = x2.max(0.0).min(frames[i].width as f32);
-                y2 = y2.max(0.0).min(frames[i].height as f32);
-                let bbox = Bbox {
-                    class: class_id,
-                    score: prob,
-                    x1,
-                    y1,
-                    x2,
-                    y2,
-                };
-                boxes.push(bbox);
+                boxes.into_iter().map(Ok).collect()
             }
-        } else {
-            // Iterate bboxes
-            for row in output.axis_iter(Axis(1)) {
-                // output tensor:[x1, y1, x2, y2, prob, class_id]
-                let row: Vec<_> = row.iter().copied().collect();
-                let class_id = row[5] as usize;
-                let prob = row[4];
-                if prob < config.conf_thres {
-                    continue;
-                }
-                let mut x1 = (row[0] as f32 - frames[i].padding.0 as f32) * frames[i].ratio;
-                let mut y1 = (row[1] as f32 - frames[i].padding.1 as f32) * frames[i].ratio;
-                let mut x2 = (row[2] as f32 - frames[i].padding.0 as f32) * frames[i].ratio;
-                let mut y2 = (row[3] as f32 - frames[i].padding.1 as f32) * frames[i].ratio;
-                x1 = x1.max(0.0).min(frames[i].width as f32);
-                y1 = y1.max(0.0).min(frames[i].height as f32);
-                x2 = x2.max(0.0).min(frames[i].width as f32);
-                y2 = y2.max(0.0).min(frames[i].height as f32);
-                let bbox = Bbox {
-                    class: class_id,
-                    score: prob,
-                    x1,
-                    y1,
-                    x2,
-                    y2,
-                };
-                boxes.push(bbox);
+            Err(e) => {
+                log::error!("{} ({} frames affected)", e, frames.len());
+                frames.iter().map(|_| Err(e.to_string())).collect()
             }
+        };
 
-            boxes = nms(&mut boxes, true, 100, config.iou_thres);
-        }
-
-        let label = get_label(&boxes, &config.class_map);
-
-        let shoot_time = match frames[i].shoot_time {
+    for (frame, boxes) in frames.iter().zip(results) {
+        let shoot_time = match frame.shoot_time {
             Some(shoot_time) => Some(shoot_time.to_string()),
             None => None,
         };
 
+        let (bboxes, label, error) = match boxes {
+            Ok(boxes) => match get_label(&boxes, &config.class_map) {
+                Ok(label) => (Some(boxes), Some(label), classify_error.clone()),
+                Err(e) => (Some(boxes), None, Some(e.to_string())),
+            },
+            Err(e) => (None, None, Some(e)),
+        };
+
         let export_frame = ExportFrame {
-            file: frames[i].file.clone(),
+            file: frame.file.clone(),
             shoot_time,
-            frame_index: frames[i].frame_index,
-            total_frames: frames[i].total_frames,
-            bboxes: Some(boxes),
-            label: Some(label),
-            error: None,
-            iframe: frames[i].iframe,
+            shoot_time_source: frame.shoot_time_source,
+            orientation: frame.orientation,
+            width: Some(frame.width),
+            height: Some(frame.height),
+            frame_index: frame.frame_index,
+            total_frames: frame.total_frames,
+            bboxes,
+            label,
+            error,
+            iframe: frame.iframe,
+            timestamp: frame.timestamp,
+            video_duration: frame.video_duration,
+            video_fps: frame.video_fps,
+            sequence_id: None,
         };
-        export_q_s.send(export_frame).unwrap();
+        export_q_s
+            .send(export_frame)
+            .map_err(|_| DetectError::ExportClosed)?;
     }
     Ok(())
 }
 
-fn get_label(bboxes: &Vec<Bbox>, cls_map: &HashMap<usize, String>) -> HashSet<String> {
+/// Boxes of each frame in original image coordinates. With tiling, tiles of
+/// all frames share the batch path and are merged per frame with `nms`.
+fn detect_frames(
+    frames: &[Frame],
+    model: &Session,
+    adapter: &dyn ModelAdapter,
+    config: &DetectConfig,
+) -> Result<Vec<Vec<Bbox>>, DetectError> {
+    let tiles: Vec<Vec<Tile>> = frames
+        .iter()
+        .map(|frame| match (&config.tiling, &frame.original) {
+            (Some(tiling), Some(original)) => make_tiles(
+                original,
+                config.target_size,
+                tiling,
+                config.preprocess.pad_color,
+            ),
+            _ => Vec::new(),
+        })
+        .collect();
+    let full_frame = config.tiling.map_or(true, |tiling| tiling.full_frame);
+
+    // Owning frame and offset of every model input
+    let mut owners = Vec::new();
+    let mut inputs: Vec<ModelInput> = Vec::new();
+    for (i, frame) in frames.iter().enumerate() {
+        if full_frame || tiles[i].is_empty() {
+            owners.push((i, 0.0, 0.0));
+            inputs.push(frame.into());
+        }
+        for tile in &tiles[i] {
+            owners.push((i, tile.x as f32, tile.y as f32));
+            inputs.push(tile.input());
+        }
+    }
+
+    let mut results = vec![Vec::new(); frames.len()];
+    let batch_size = config.batch_size.max(1);
+    for (owners, inputs) in owners.chunks(batch_size).zip(inputs.chunks(batch_size)) {
+        let boxes = adapter.infer(inputs, model, config)?;
+        for (&(i, dx, dy), boxes) in owners.iter().zip(boxes) {
+            results[i].extend(boxes.into_iter().map(|mut bbox| {
+                bbox.x1 += dx;
+                bbox.y1 += dy;
+                bbox.x2 += dx;
+                bbox.y2 += dy;
+                bbox
+            }));
+        }
+    }
+    for (boxes, tiles) in results.iter_mut().zip(&tiles) {
+        if !tiles.is_empty() {
+            *boxes = nms(boxes, true, 100, config.iou_thres);
+        }
+    }
+    Ok(results)
+}
+
+fn get_label(
+    bboxes: &Vec<Bbox>,
+    cls_map: &HashMap<usize, String>,
+) -> Result<HashSet<String>, DetectError> {
     let mut labels = HashSet::new();
     if bboxes.is_empty() {
         labels.insert("Blank".to_string());
-        return labels;
+        return Ok(labels);
     }
 
     for bbox in bboxes {
@@ -322,10 +458,38 @@ fn get_label(bboxes: &Vec<Bbox>, cls_map: &HashMap<usize, String>) -> HashSet<St
 
         let label = match cls_map.get(&class_id) {
             Some(label) => label.to_string(),
-            None => Err(anyhow!("Class ID not found")).unwrap(),
+            None => return Err(DetectError::UnknownClass(class_id)),
         };
 
         labels.insert(label);
+        if let Some(species) = bbox.species.as_ref().and_then(|species| species.first()) {
+            labels.insert(species.label.clone());
+        }
+    }
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_class_thresholds() {
+        let class_map = HashMap::from([
+            (0, "Animal".to_string()),
+            (1, "Person".to_string()),
+            (2, "Vehicle".to_string()),
+        ]);
+        let per_class = BTreeMap::from([("Person".to_string(), 0.6)]);
+
+        let thresholds =
+            class_thresholds(&class_map, 0.2, &per_class, &[], &["Vehicle".to_string()]).unwrap();
+        assert_eq!(thresholds, HashMap::from([(0, 0.2), (1, 0.6)]));
+
+        let thresholds =
+            class_thresholds(&class_map, 0.2, &per_class, &["Animal".to_string()], &[]).unwrap();
+        assert_eq!(thresholds, HashMap::from([(0, 0.2)]));
+
+        assert!(class_thresholds(&class_map, 0.2, &per_class, &["Bird".to_string()], &[]).is_err());
     }
-    labels
 }
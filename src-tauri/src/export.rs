@@ -1,59 +1,253 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
+use chrono::{DateTime, FixedOffset};
 use csv::WriterBuilder;
 use log::info;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
+use crate::adapter::Architecture;
+use crate::camtrap::write_camtrap_dp;
+use crate::coco::{
+    dataset_metadata, import_coco_camera_traps, write_coco_camera_traps, CocoCameraTraps,
+};
+use crate::media::{MediaExtensions, TimeSource, VideoSampling};
+use crate::megadetector::write_batch_output;
+use crate::pipeline::PipelineObserver;
+use crate::sequence::assign_sequences;
+use crate::store::{read_store, ResultStore};
+use crate::tiling::Tiling;
 use crate::utils::{Bbox, FileItem};
-use crate::ExportFormat;
+use crate::{ConfigOptions, EpConfig, ExportFormat};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("Failed to write result: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Failed to serialize result: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("Failed to write csv: {0}")]
+    CsvError(#[from] csv::Error),
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExportFrame {
     #[serde(flatten)]
     pub file: FileItem,
     pub shoot_time: Option<String>,
+    pub shoot_time_source: Option<TimeSource>,
+    /// EXIF orientation the image was rotated by before detection
+    pub orientation: Option<u16>,
+    /// Size of the frame in the displayed orientation, bboxes are in its pixels
+    pub width: Option<usize>,
+    pub height: Option<usize>,
     pub frame_index: usize,
     pub total_frames: usize,
     pub bboxes: Option<Vec<Bbox>>,
     pub label: Option<HashSet<String>>,
     pub error: Option<String>,
     pub iframe: bool,
+    /// Presentation time of a video frame in seconds from the start of the video
+    pub timestamp: Option<f64>,
+    pub video_duration: Option<f64>,
+    pub video_fps: Option<f64>,
+    /// Shots of one trigger share a sequence, see [`crate::sequence`]
+    pub sequence_id: Option<String>,
+}
+
+/// Version of the result file layout, bumped when readers need to tell files apart.
+/// JSON results before version 2 are a bare array of frames.
+pub const RESULT_FORMAT_VERSION: u32 = 2;
+
+/// What produced a result file. Written around the frames of `result.json`
+/// and into `result.meta.json` next to `result.csv`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunMetadata {
+    pub info: RunInfo,
+    pub config: RunConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunInfo {
+    pub format_version: u32,
+    pub app_version: String,
+    pub model: ModelInfo,
+    /// Name of the species classifier, if one ran
+    pub classifier: Option<String>,
+    pub ep: Vec<EpConfig>,
+    /// RFC 3339 local time
+    pub start_time: String,
+    /// Missing in checkpoints written while the job is running
+    pub end_time: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub path: PathBuf,
+    /// Class names, a bbox `class` is an index into them
+    pub classes: Vec<String>,
+    /// md5 of the model file that was loaded
+    pub md5: Option<String>,
+    pub architecture: Architecture,
+}
+
+/// Options that change which frames are detected and which boxes are kept.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub confidence_threshold: f32,
+    pub iou_threshold: f32,
+    /// Effective threshold of each class, filtered out classes are missing
+    pub class_thresholds: BTreeMap<String, f32>,
+    pub iframe_only: bool,
+    pub max_frames: Option<usize>,
+    pub video_sampling: VideoSampling,
+    pub extensions: MediaExtensions,
+    pub tiling: Option<Tiling>,
+}
+
+#[derive(Serialize)]
+struct ResultJson<'a> {
+    info: &'a RunInfo,
+    config: &'a RunConfig,
+    frames: &'a [ExportFrame],
+}
+
+#[derive(Deserialize)]
+struct ResultFile {
+    info: RunInfo,
+    config: RunConfig,
+    frames: Vec<ExportFrame>,
+}
+
+/// Parse a `shoot_time` as written by detection, `2024-05-01 12:00:03 +08:00`.
+pub fn parse_shoot_time(shoot_time: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(shoot_time, "%Y-%m-%d %H:%M:%S%.f %:z").ok()
+}
+
+/// Sidecar metadata file of a CSV result, `result.meta.json` for `result.csv`.
+pub fn metadata_path<P: AsRef<Path>>(result: P) -> PathBuf {
+    result.as_ref().with_extension("meta.json")
+}
+
+/// Frames and metadata of a result file or store, or of a COCO Camera Traps
+/// dataset. Metadata is `None` for results written before it was recorded.
+pub fn read_result<P: AsRef<Path>>(path: P) -> Result<(Vec<ExportFrame>, Option<RunMetadata>)> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == "jsonl") {
+        return read_store(path);
+    }
+    if path.extension().is_some_and(|ext| ext == "csv") {
+        let frames = parse_export_csv(path)?;
+        let sidecar = metadata_path(path);
+        let metadata = if sidecar.is_file() {
+            Some(serde_json::from_str(&std::fs::read_to_string(sidecar)?)?)
+        } else {
+            None
+        };
+        return Ok((frames, metadata));
+    }
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if json.is_array() {
+        return Ok((serde_json::from_value(json)?, None));
+    }
+    if json.get("images").is_some() && json.get("annotations").is_some() {
+        let mut coco: CocoCameraTraps = serde_json::from_value(json)?;
+        let run = coco.info.run.take();
+        // Files are relative to the dataset, like results in the media folder
+        let path = std::fs::canonicalize(path)?;
+        let folder_path = path.parent().unwrap_or(Path::new("."));
+        let (frames, classes) = import_coco_camera_traps(&coco, folder_path)?;
+        let metadata = run.unwrap_or_else(|| dataset_metadata(&coco.info, classes));
+        return Ok((frames, Some(metadata)));
+    }
+    let result: ResultFile = serde_json::from_value(json)?;
+    Ok((
+        result.frames,
+        Some(RunMetadata {
+            info: result.info,
+            config: result.config,
+        }),
+    ))
 }
 
+/// Columns of `result.csv`, readers look columns up by name so older files
+/// without the later columns still parse.
+const CSV_HEADER: [&str; 18] = [
+    "folder_id",
+    "file_id",
+    "file_path",
+    "shoot_time",
+    "frame_index",
+    "total_frames",
+    "bboxes",
+    "label",
+    "error",
+    "iframe",
+    "timestamp",
+    "video_duration",
+    "video_fps",
+    "shoot_time_source",
+    "orientation",
+    "width",
+    "height",
+    "sequence_id",
+];
+
 pub fn parse_export_csv<P: AsRef<Path>>(csv: P) -> Result<Vec<ExportFrame>> {
     let file = File::open(csv)?;
     let mut rdr = csv::Reader::from_reader(file);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name);
+    let columns: Vec<Option<usize>> = CSV_HEADER.iter().map(|name| column(name)).collect();
     let mut export_data = Vec::new();
     for frame in rdr.records() {
         let frame = frame?;
+        // Value of a column by its index in CSV_HEADER, None if missing or empty
+        let field = |i: usize| {
+            columns[i]
+                .and_then(|c| frame.get(c))
+                .filter(|value| !value.is_empty())
+        };
+        let required = |i: usize| {
+            field(i).ok_or_else(|| anyhow::anyhow!("Missing {} in result csv", CSV_HEADER[i]))
+        };
         let file_item = FileItem {
-            folder_id: frame[0].parse::<_>()?,
-            file_id: frame[1].parse::<_>()?,
-            file_path: frame[2].parse()?,
-            tmp_path: frame[2].parse()?,
+            folder_id: required(0)?.parse::<_>()?,
+            file_id: required(1)?.parse::<_>()?,
+            file_path: required(2)?.parse()?,
+            tmp_path: required(2)?.parse()?,
+        };
+        let bboxes = match field(6) {
+            Some(bboxes) => serde_json::from_str(&bboxes.replace("\"\"", "\""))?,
+            None => None,
         };
-        let bboxes = frame[7].to_string().replace("\"\"", "\"");
-        let bboxes = serde_json::from_str(&bboxes)?;
         let frame_item = ExportFrame {
             file: file_item,
-            shoot_time: Some(frame[3].to_string()),
-            frame_index: frame[4].parse::<_>()?,
-            total_frames: frame[5].parse::<_>()?,
+            shoot_time: field(3).map(|s| s.to_string()),
+            frame_index: required(4)?.parse::<_>()?,
+            total_frames: required(5)?.parse::<_>()?,
             bboxes,
-            label: Some(
-                frame[8]
-                    .to_string()
-                    .split(";")
-                    .map(|s| s.to_string())
-                    .collect(),
-            ),
-            error: Some(frame[9].to_string()),
-            iframe: frame[10].parse::<bool>()?,
+            label: field(7).map(|label| label.split(";").map(|s| s.to_string()).collect()),
+            error: field(8).map(|s| s.to_string()),
+            iframe: field(9)
+                .map(|s| s.parse::<bool>())
+                .transpose()?
+                .unwrap_or(false),
+            timestamp: field(10).map(|s| s.parse::<f64>()).transpose()?,
+            video_duration: field(11).map(|s| s.parse::<f64>()).transpose()?,
+            video_fps: field(12).map(|s| s.parse::<f64>()).transpose()?,
+            shoot_time_source: field(13).map(|s| s.parse::<TimeSource>()).transpose()?,
+            orientation: field(14).map(|s| s.parse::<u16>()).transpose()?,
+            width: field(15).map(|s| s.parse::<usize>()).transpose()?,
+            height: field(16).map(|s| s.parse::<usize>()).transpose()?,
+            sequence_id: field(17).map(|s| s.to_string()),
         };
         export_data.push(frame_item);
     }
@@ -62,56 +256,66 @@ pub fn parse_export_csv<P: AsRef<Path>>(csv: P) -> Result<Vec<ExportFrame>> {
 
 pub fn export_worker(
     checkpoint: usize,
-    checkpoint_counter: &Arc<Mutex<usize>>,
-    format: &ExportFormat,
-    folder_path: &PathBuf,
+    store: &ResultStore,
     export_q_r: crossbeam_channel::Receiver<ExportFrame>,
-    export_data: &Arc<Mutex<Vec<ExportFrame>>>,
+    observer: &dyn PipelineObserver,
 ) {
     loop {
         match export_q_r.recv() {
             Ok(export_frame) => {
-                let mut checkpoint_counter = checkpoint_counter.lock().unwrap();
-                if *checkpoint_counter % checkpoint == 0 && *checkpoint_counter != 0 {
-                    let export_data = export_data.lock().unwrap();
-                    info!("Exported {} frames", export_data.len());
-                    match format {
-                        ExportFormat::Json => write_json(&export_data, folder_path).unwrap(),
-                        ExportFormat::Csv => write_csv(&export_data, folder_path).unwrap(),
+                if export_frame.error.is_some() {
+                    observer.on_file_error(&export_frame);
+                }
+                match store.insert(&export_frame) {
+                    Ok(inserted) if inserted % checkpoint == 0 => {
+                        info!("Exported {} frames", inserted);
+                        // A failed checkpoint is retried at the next one, keep detecting
+                        if let Err(e) = store.commit() {
+                            log::error!("Failed to write checkpoint: {}", e);
+                        }
                     }
+                    Ok(_) => {}
+                    Err(e) => log::error!(
+                        "Failed to store frame of {}: {}",
+                        export_frame.file.file_path.display(),
+                        e
+                    ),
                 }
-                export_data.lock().unwrap().push(export_frame);
-                *checkpoint_counter += 1;
             }
             Err(_) => break,
         }
     }
 }
 
-fn write_json(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()> {
-    let json = serde_json::to_string_pretty(export_data)?;
+fn write_json(
+    export_data: &Vec<ExportFrame>,
+    metadata: &RunMetadata,
+    folder_path: &PathBuf,
+) -> Result<(), ExportError> {
+    let json = serde_json::to_string_pretty(&ResultJson {
+        info: &metadata.info,
+        config: &metadata.config,
+        frames: export_data,
+    })?;
     let json_path = folder_path.join("result.json");
     let mut file = File::create(json_path)?;
     file.write_all(json.as_bytes())?;
     Ok(())
 }
 
-fn write_csv(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()> {
+fn write_csv(
+    export_data: &Vec<ExportFrame>,
+    metadata: &RunMetadata,
+    folder_path: &PathBuf,
+) -> Result<(), ExportError> {
     let csv_path = folder_path.join("result.csv");
+    let json = serde_json::to_string_pretty(metadata)?;
+    std::fs::write(metadata_path(&csv_path), json)?;
     let mut wtr = WriterBuilder::new()
         .has_headers(false)
         .from_path(csv_path)?;
-    wtr.write_record([
-        "folder_id",
-        "file_id",
-        "file_path",
-        "shoot_time",
-        "frame_index",
-        "total_frames",
-        "bboxes",
-        "label",
-        "error",
-    ])?;
+    wtr.write_record(CSV_HEADER)?;
+    let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
     for export_frame in export_data {
         wtr.write_record(&[
             export_frame.file.folder_id.to_string().as_str(),
@@ -144,32 +348,79 @@ fn write_csv(export_data: &Vec<ExportFrame>, folder_path: &PathBuf) -> Result<()
                 .clone()
                 .unwrap_or("".to_string())
                 .as_str(),
+            export_frame.iframe.to_string().as_str(),
+            optional(export_frame.timestamp).as_str(),
+            optional(export_frame.video_duration).as_str(),
+            optional(export_frame.video_fps).as_str(),
+            export_frame
+                .shoot_time_source
+                .map(|source| source.as_str())
+                .unwrap_or(""),
+            export_frame
+                .orientation
+                .map(|orientation| orientation.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            export_frame
+                .width
+                .map(|width| width.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            export_frame
+                .height
+                .map(|height| height.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            export_frame.sequence_id.as_deref().unwrap_or_default(),
         ])?;
     }
     wtr.flush()?;
     Ok(())
 }
 
+/// Write `result.json` or `result.csv`, the formats other tools read back.
+fn write_native(
+    export_data: &Vec<ExportFrame>,
+    metadata: &RunMetadata,
+    format: &ExportFormat,
+    folder_path: &PathBuf,
+) -> Result<(), ExportError> {
+    match format.native() {
+        ExportFormat::Csv => write_csv(export_data, metadata, folder_path),
+        _ => write_json(export_data, metadata, folder_path),
+    }
+}
+
+/// Generate the result files of a job from the frames of its store, after
+/// grouping the frames into sequences.
 pub fn export(
     folder_path: &PathBuf,
-    export_data: Arc<Mutex<Vec<ExportFrame>>>,
-    export_format: &ExportFormat,
-) -> Result<()> {
-    let export_data = Arc::try_unwrap(export_data).unwrap().into_inner().unwrap();
+    export_data: &mut Vec<ExportFrame>,
+    metadata: &RunMetadata,
+    config_options: &ConfigOptions,
+) -> Result<(), ExportError> {
+    let export_format = &config_options.export_format;
+    assign_sequences(export_data, folder_path, &config_options.sequence);
     info!("Exported {} frames", export_data.len());
+    write_native(export_data, metadata, export_format, folder_path)?;
     match export_format {
-        ExportFormat::Json => {
-            write_json(&export_data, folder_path)?;
-        }
-        ExportFormat::Csv => {
-            write_csv(&export_data, folder_path)?;
+        ExportFormat::MegaDetector => write_batch_output(export_data, metadata, folder_path)?,
+        ExportFormat::CocoCameraTraps => {
+            write_coco_camera_traps(export_data, metadata, folder_path)?
         }
+        ExportFormat::CamtrapDp => write_camtrap_dp(
+            export_data,
+            metadata,
+            &config_options.camtrap_dp,
+            folder_path,
+        )?,
+        ExportFormat::Json | ExportFormat::Csv => {}
     }
     Ok(())
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
 
     #[test]
@@ -178,4 +429,145 @@ mod tests {
         let export_data = parse_export_csv("input/result.csv").unwrap();
         assert_eq!(export_data.len(), 11);
     }
+
+    pub(crate) fn test_frame() -> ExportFrame {
+        ExportFrame {
+            file: FileItem::new(1, 2, PathBuf::from("a/b.mp4"), None),
+            shoot_time: Some("2024-05-01 12:00:03 +08:00".to_string()),
+            shoot_time_source: Some(TimeSource::Container),
+            orientation: None,
+            width: Some(1920),
+            height: Some(1080),
+            frame_index: 3,
+            total_frames: 5,
+            bboxes: Some(vec![Bbox {
+                x1: 1.0,
+                y1: 2.0,
+                x2: 3.0,
+                y2: 4.0,
+                score: 0.5,
+                class: 0,
+                species: None,
+            }]),
+            label: Some(HashSet::from(["Animal".to_string()])),
+            error: None,
+            iframe: true,
+            timestamp: Some(3.0),
+            video_duration: Some(10.0),
+            video_fps: Some(29.97),
+            sequence_id: None,
+        }
+    }
+
+    pub(crate) fn test_metadata() -> RunMetadata {
+        RunMetadata {
+            info: RunInfo {
+                format_version: RESULT_FORMAT_VERSION,
+                app_version: "0.2.0".to_string(),
+                model: ModelInfo {
+                    name: "MDV6-yolov9e".to_string(),
+                    path: PathBuf::from("models/MDV6-yolov9e.onnx"),
+                    classes: vec![
+                        "Animal".to_string(),
+                        "Person".to_string(),
+                        "Vehicle".to_string(),
+                    ],
+                    md5: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+                    architecture: Architecture::YoloNms,
+                },
+                classifier: None,
+                ep: Vec::new(),
+                start_time: "2024-05-01T12:00:00+08:00".to_string(),
+                end_time: None,
+            },
+            config: RunConfig {
+                confidence_threshold: 0.2,
+                iou_threshold: 0.45,
+                class_thresholds: BTreeMap::from([("Animal".to_string(), 0.2)]),
+                iframe_only: true,
+                max_frames: Some(3),
+                video_sampling: VideoSampling::default(),
+                extensions: MediaExtensions::default(),
+                tiling: None,
+            },
+        }
+    }
+
+    /// A still of `path` with one box of `class`, or no boxes.
+    pub(crate) fn still(
+        path: impl AsRef<Path>,
+        shoot_time: Option<&str>,
+        class: Option<usize>,
+    ) -> ExportFrame {
+        let mut frame = test_frame();
+        frame.file.file_path = path.as_ref().to_path_buf();
+        frame.shoot_time = shoot_time.map(String::from);
+        frame.frame_index = 0;
+        frame.total_frames = 1;
+        frame.video_fps = None;
+        let bboxes = frame.bboxes.as_mut().unwrap();
+        match class {
+            Some(class) => bboxes[0].class = class,
+            None => bboxes.clear(),
+        }
+        frame
+    }
+
+    /// Folder under the system temp dir, removed on drop even when a test fails.
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("megascops-{}-{}", name, uuid::Uuid::new_v4()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let tmp = TempDir::new("result");
+        let folder = tmp.path().to_path_buf();
+        write_csv(&vec![test_frame()], &test_metadata(), &folder).unwrap();
+
+        let (parsed, metadata) = read_result(folder.join("result.csv")).unwrap();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].file.file_path, PathBuf::from("a/b.mp4"));
+        assert_eq!(parsed[0].bboxes.as_ref().unwrap().len(), 1);
+        assert_eq!(parsed[0].error, None);
+        assert!(parsed[0].iframe);
+        assert_eq!(parsed[0].timestamp, Some(3.0));
+        assert_eq!(parsed[0].video_fps, Some(29.97));
+        assert_eq!(parsed[0].shoot_time_source, Some(TimeSource::Container));
+        assert_eq!(metadata.unwrap().config, test_metadata().config);
+    }
+
+    #[test]
+    fn test_read_result_json() {
+        let tmp = TempDir::new("result");
+        let folder = tmp.path().to_path_buf();
+        write_json(&vec![test_frame()], &test_metadata(), &folder).unwrap();
+        let (frames, metadata) = read_result(folder.join("result.json")).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(metadata.unwrap().info.model, test_metadata().info.model);
+
+        // Results written before the envelope are a bare array of frames
+        let legacy = folder.join("legacy.json");
+        std::fs::write(&legacy, serde_json::to_string(&vec![test_frame()]).unwrap()).unwrap();
+        let (frames, metadata) = read_result(&legacy).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(metadata.is_none());
+    }
 }
@@ -0,0 +1,388 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::StreamExt;
+use md5::{Digest, Md5};
+use serde::Serialize;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tauri_plugin_store::StoreExt;
+
+use crate::ep::get_devices;
+use crate::organize::{dry_run, organize, undo, OrganizeOptions, OrganizePlan, OrganizeSummary};
+use crate::pipeline::{Pipeline, PipelineObserver};
+use crate::utils::file_md5;
+use crate::{Config, JobControl};
+
+#[tauri::command]
+async fn list_devices(app: AppHandle) {
+    if let Ok(devices) = get_devices() {
+        app.emit("devices", devices).unwrap();
+    }
+}
+
+#[tauri::command]
+fn calculate_md5(file_path: String) -> Result<String, String> {
+    file_md5(file_path).map_err(|e| e.to_string())
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct DownloadProgress {
+    model_name: String,
+    progress: f32,
+    finished: bool,
+    error: Option<String>,
+}
+
+#[tauri::command]
+async fn download_model(
+    url: String,
+    destination: String,
+    model_name: String,
+    app: AppHandle,
+    md5: String,
+) -> Result<(), String> {
+    tokio::spawn(async move {
+        log::info!("Downloading model from {}", url);
+        let client = reqwest::Client::new();
+
+        let resp = match client.head(&url).send().await {
+            Ok(resp) => resp,
+            Err(e) => {
+                log::error!("Could not connect to server: {}", e);
+                let _ = app.emit(
+                    "download-progress",
+                    DownloadProgress {
+                        model_name: model_name.clone(),
+                        progress: 0.0,
+                        finished: true,
+                        error: Some(format!("Could not connect to server: {}", e)),
+                    },
+                );
+                return;
+            }
+        };
+
+        let mut total_size = resp.content_length().unwrap_or(0);
+
+        let resp = match client.get(&url).send().await {
+            Ok(resp) => {
+                if !resp.status().is_success() {
+                    let _ = app.emit(
+                        "download-progress",
+                        DownloadProgress {
+                            model_name: model_name.clone(),
+                            progress: 0.0,
+                            finished: true,
+                            error: Some(format!("Request failed: {}", resp.status())),
+                        },
+                    );
+                    return;
+                }
+                log::info!("Download started");
+                if let Some(content_length) = resp.headers().get("content-length") {
+                    if let Ok(content_length) = content_length.to_str() {
+                        if let Ok(content_length) = content_length.parse::<u64>() {
+                            total_size = content_length;
+                        }
+                    }
+                }
+                resp
+            }
+            Err(e) => {
+                let _ = app.emit(
+                    "download-progress",
+                    DownloadProgress {
+                        model_name: model_name.clone(),
+                        progress: 0.0,
+                        finished: true,
+                        error: Some(format!("Download failed: {}", e)),
+                    },
+                );
+                return;
+            }
+        };
+
+        let mut file = match tokio::fs::File::create(&destination).await {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = app.emit(
+                    "download-progress",
+                    DownloadProgress {
+                        model_name: model_name.clone(),
+                        progress: 0.0,
+                        finished: true,
+                        error: Some(format!("Create file failed: {}", e)),
+                    },
+                );
+                return;
+            }
+        };
+
+        let mut downloaded: u64 = 0;
+        let mut stream = resp.bytes_stream();
+        let mut hasher = Md5::new();
+
+        let mut last_update = Instant::now();
+        let update_interval = Duration::from_millis(250);
+
+        while let Some(item) = stream.next().await {
+            let chunk = match item {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = app.emit(
+                        "download-progress",
+                        DownloadProgress {
+                            model_name: model_name.clone(),
+                            progress: 0.0,
+                            finished: true,
+                            error: Some(format!("Download failed: {}", e)),
+                        },
+                    );
+                    return;
+                }
+            };
+
+            // Update MD5 hash with the chunk
+            hasher.update(&chunk);
+
+            if let Err(e) = tokio::io::copy(&mut chunk.as_ref(), &mut file).await {
+                let _ = app.emit(
+                    "download-progress",
+                    DownloadProgress {
+                        model_name: model_name.clone(),
+                        progress: 0.0,
+                        finished: true,
+                        error: Some(format!("Copy file failed: {}", e)),
+                    },
+                );
+                return;
+            }
+
+            downloaded += chunk.len() as u64;
+
+            if total_size > 0 {
+                let progress = (downloaded as f32 / total_size as f32) * 100.0;
+                let now = Instant::now();
+                if now.duration_since(last_update) >= update_interval {
+                    let _ = app.emit(
+                        "download-progress",
+                        DownloadProgress {
+                            model_name: model_name.clone(),
+                            progress,
+                            finished: false,
+                            error: None,
+                        },
+                    );
+                    last_update = now;
+                }
+            }
+        }
+
+        // Verify MD5 checksum
+        let calculated_md5 = format!("{:x}", hasher.finalize());
+        if !md5.is_empty() && calculated_md5 != md5.to_lowercase() {
+            log::error!(
+                "MD5 checksum verification failed. Expected: {}, Got: {}",
+                md5,
+                calculated_md5
+            );
+            let _ = app.emit(
+                "download-progress",
+                DownloadProgress {
+                    model_name: model_name.clone(),
+                    progress: 100.0,
+                    finished: true,
+                    error: Some(format!(
+                        "MD5 checksum verification failed. Expected: {}, Got: {}",
+                        md5, calculated_md5
+                    )),
+                },
+            );
+
+            // Delete the corrupted file
+            if let Err(e) = tokio::fs::remove_file(&destination).await {
+                log::error!("Failed to delete corrupted file: {}", e);
+            }
+            return;
+        }
+
+        log::info!("Download completed and MD5 checksum verified successfully");
+        let _ = app.emit(
+            "download-progress",
+            DownloadProgress {
+                model_name,
+                progress: 100.0,
+                finished: true,
+                error: None,
+            },
+        );
+    });
+
+    Ok(())
+}
+
+struct TauriObserver {
+    app: AppHandle,
+}
+
+impl PipelineObserver for TauriObserver {
+    fn on_progress(&self, processed: usize, total: usize) {
+        let progress = processed as f32 / total.max(1) as f32 * 100.0;
+        self.app.emit("detect-progress", progress as usize).unwrap();
+    }
+}
+
+#[tauri::command]
+async fn process_media(app: AppHandle, config: Config) {
+    let control = Arc::clone(app.state::<Arc<JobControl>>().inner());
+    control.reset();
+
+    let pipeline = Pipeline::builder()
+        .config(config)
+        .control(control)
+        .observer(Arc::new(TauriObserver { app: app.clone() }))
+        .build();
+
+    // A paused job blocks in `run`, keep it off the async runtime
+    let result =
+        tauri::async_runtime::spawn_blocking(move || pipeline.and_then(|pipeline| pipeline.run()))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result);
+    match result {
+        Ok(summary) if summary.cancelled => {
+            app.emit(
+                "detect-cancelled",
+                summary.store_path.to_string_lossy().to_string(),
+            )
+            .unwrap();
+        }
+        Ok(_) => {
+            app.emit("detect-complete", 1).unwrap();
+        }
+        Err(e) => {
+            app.emit("detect-error", e.to_string()).unwrap();
+            log::error!("Error processing: {}", e);
+        }
+    }
+}
+
+#[tauri::command]
+fn cancel_detection(control: State<'_, Arc<JobControl>>) {
+    log::info!("Cancelling detection");
+    control.cancel();
+}
+
+#[tauri::command]
+fn pause_detection(control: State<'_, Arc<JobControl>>) {
+    log::info!("Pausing detection");
+    control.pause();
+}
+
+#[tauri::command]
+fn resume_detection(control: State<'_, Arc<JobControl>>) {
+    log::info!("Resuming detection");
+    control.resume();
+}
+
+/// Emits `event` with the percentage done, only when it changes.
+fn progress_emitter(app: AppHandle, event: &'static str) -> impl Fn(usize, usize) {
+    let last = AtomicUsize::new(usize::MAX);
+    move |done, total| {
+        let progress = done * 100 / total.max(1);
+        if last.swap(progress, Ordering::Relaxed) != progress {
+            let _ = app.emit(event, progress);
+        }
+    }
+}
+
+#[tauri::command]
+async fn organize_media(
+    app: AppHandle,
+    result_path: String,
+    options: OrganizeOptions,
+) -> Result<OrganizeSummary, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let on_progress = progress_emitter(app, "organize-progress");
+        organize(result_path, &options, &on_progress)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| {
+        log::error!("Error organizing: {}", e);
+        e.to_string()
+    })
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OrganizePreview {
+    plan: OrganizePlan,
+    summary: String,
+}
+
+#[tauri::command]
+async fn preview_organize(
+    result_path: String,
+    options: OrganizeOptions,
+) -> Result<OrganizePreview, String> {
+    tauri::async_runtime::spawn_blocking(move || dry_run(result_path, &options))
+        .await
+        .map_err(|e| e.to_string())?
+        .map(|plan| OrganizePreview {
+            summary: plan.to_string(),
+            plan,
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn undo_organize(app: AppHandle, folder: String) -> Result<OrganizeSummary, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let on_progress = progress_emitter(app, "organize-progress");
+        undo(folder, &on_progress)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| {
+        log::error!("Error undoing organize: {}", e);
+        e.to_string()
+    })
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_store::Builder::default().build())
+        .plugin(
+            tauri_plugin_log::Builder::new()
+                .level(log::LevelFilter::Info)
+                .filter(|metadata| metadata.target() != "hyper")
+                .build(),
+        )
+        .plugin(tauri_plugin_shell::init())
+        .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_dialog::init())
+        .plugin(tauri_plugin_opener::init())
+        .invoke_handler(tauri::generate_handler![
+            process_media,
+            cancel_detection,
+            pause_detection,
+            resume_detection,
+            list_devices,
+            download_model,
+            calculate_md5,
+            organize_media,
+            preview_organize,
+            undo_organize,
+        ])
+        .setup(|app| {
+            let _ = app.store("store.json")?;
+            app.manage(Arc::new(JobControl::new()));
+            Ok(())
+        })
+        .run(tauri::generate_context!())
+        .expect("error while running tauri application");
+}
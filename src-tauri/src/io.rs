@@ -9,17 +9,27 @@ use crate::utils::FileItem;
 
 fn copy_to_buff(file_path: &PathBuf, buff_path: &Path) -> Result<PathBuf> {
     let mut tmp_name = Uuid::new_v4().to_string();
-    let ext = file_path.extension().unwrap();
-    tmp_name.push_str(".");
-    tmp_name.push_str(ext.to_str().unwrap());
+    if let Some(ext) = file_path.extension() {
+        tmp_name.push_str(".");
+        tmp_name.push_str(&ext.to_string_lossy());
+    }
     let temp_path = buff_path.join(tmp_name);
     fs::copy(file_path, &temp_path)?;
     Ok(temp_path)
 }
 
+/// Copy `file` into the buffer folder and queue it. If the copy fails the file
+/// is queued unbuffered so it is still decoded from its original location.
 pub fn io_worker(buff_path: &Path, file: &FileItem, io_q_s: Sender<FileItem>) -> Result<()> {
     let mut new_file = file.clone();
-    new_file.tmp_path = copy_to_buff(&file.file_path, buff_path)?;
+    match copy_to_buff(&file.file_path, buff_path) {
+        Ok(tmp_path) => new_file.tmp_path = tmp_path,
+        Err(e) => log::warn!(
+            "Failed to buffer {}, reading it in place: {}",
+            file.file_path.display(),
+            e
+        ),
+    }
     io_q_s.send(new_file)?;
     Ok(())
 }
@@ -1,30 +1,41 @@
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
 
-use anyhow::Result;
-use crossbeam_channel::{bounded, unbounded};
-use futures_util::stream::StreamExt;
-use log::error;
-use md5::{Digest, Md5};
-use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, Emitter};
-use tauri_plugin_store::StoreExt;
 
+use camtrap::CamtrapDpOptions;
+use sequence::SequenceOptions;
+
+pub mod adapter;
+pub mod camtrap;
+pub mod classifier;
+pub mod coco;
+pub mod control;
 pub mod detect;
 pub mod ep;
 pub mod export;
+#[cfg(feature = "gui")]
+mod gui;
 pub mod io;
 pub mod media;
+pub mod megadetector;
+pub mod organize;
+pub mod pipeline;
+pub mod report;
+pub mod sequence;
+pub mod store;
+pub mod template;
+pub mod tiling;
 pub mod utils;
 
+pub use control::JobControl;
 pub use detect::{detect_worker, DetectConfig};
 pub use ep::get_devices;
 pub use export::{export, export_worker, parse_export_csv, ExportFrame};
-pub use media::media_worker;
+#[cfg(feature = "gui")]
+pub use gui::run;
+pub use media::{media_worker, MediaExtensions, VideoSampling};
+pub use pipeline::{NoopObserver, Pipeline, PipelineBuilder, PipelineObserver, RunSummary};
+pub use tiling::Tiling;
 use utils::Ep;
 pub use utils::{index_files_and_folders, load_model_config, FileItem};
 
@@ -54,6 +65,32 @@ pub struct ConfigOptions {
     pub iou_threshold: f32,
     pub export_format: ExportFormat,
     pub max_frames: Option<usize>,
+    /// Evenly sampled `max_frames`, or time based sampling which ignores `max_frames` and `iframe_only`
+    #[serde(default)]
+    pub video_sampling: VideoSampling,
+    /// Image and video extensions to index, images the image crate can't decode go through ffmpeg
+    #[serde(default)]
+    pub extensions: MediaExtensions,
+    /// Species classifier toml, run on Animal crops when set
+    #[serde(default)]
+    pub classifier: Option<String>,
+    /// Sliced inference on full resolution tiles of stills, for small and distant animals
+    #[serde(default)]
+    pub tiling: Option<Tiling>,
+    /// Confidence thresholds by class name, other classes use `confidence_threshold`
+    #[serde(default)]
+    pub class_thresholds: BTreeMap<String, f32>,
+    /// Only keep these classes, all classes when empty
+    #[serde(default)]
+    pub include_classes: Vec<String>,
+    #[serde(default)]
+    pub exclude_classes: Vec<String>,
+    /// Project, contributors and deployment coordinates of the Camtrap DP export
+    #[serde(default)]
+    pub camtrap_dp: CamtrapDpOptions,
+    /// How shots are grouped into sequences, recorded as `sequence_id` in the results
+    #[serde(default)]
+    pub sequence: SequenceOptions,
     pub iframe_only: bool,
     pub check_point: usize,
     pub buffer_path: Option<String>,
@@ -61,537 +98,85 @@ pub struct ConfigOptions {
     pub batch_size: usize,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct Config {
-    pub detect_options: DetectOptions,
-    pub config_options: ConfigOptions,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
-pub enum ExportFormat {
-    Json,
-    Csv,
-}
-
-async fn process(config: Config, progress_sender: crossbeam_channel::Sender<usize>) -> Result<()> {
-    cleanup_buffer(&config.config_options.buffer_path)?;
-
-    if config.config_options.check_point == 0 {
-        log::error!("Checkpoint should be greater than 0");
-        return Ok(());
-    }
-
-    let folder_path = std::path::PathBuf::from(&config.detect_options.selected_folder);
-    let folder_path = std::fs::canonicalize(folder_path)?;
-
-    let model_config =
-        load_model_config(config.detect_options.model).expect("Failed to load model config");
-
-    let imgsz = model_config.imgsz;
-    let max_frames = config.config_options.max_frames;
-    let start = Instant::now();
-
-    let mut file_paths = utils::index_files_and_folders(&folder_path)?;
-
-    let export_data = Arc::new(Mutex::new(Vec::new()));
-
-    let file_paths = match config.detect_options.resume_path {
-        Some(checkpoint_path) => {
-            let all_files =
-                resume_from_checkpoint(&checkpoint_path, &mut file_paths, &export_data)?;
-            all_files.to_owned()
-        }
-        None => file_paths,
-    };
-
-    let mut detect_handles = vec![];
-
-    let mut export_handles = vec![];
-
-    let mut worker_sum = 0;
-    for ep in &config.detect_options.ep {
-        worker_sum += ep.workers;
-    }
-
-    let (array_q_s, array_q_r) = bounded(config.config_options.batch_size * worker_sum * 2);
-
-    let (export_q_s, export_q_r) = unbounded();
-
-    let checkpoint_counter = Arc::new(Mutex::new(0 as usize));
-
-    for d in config.detect_options.ep.iter() {
-        let detect_config = Arc::new(DetectConfig {
-            device: d.id.clone(),
-            ep: d.ep.clone(),
-            model_path: model_config.path.clone(),
-            target_size: model_config.imgsz,
-            class_map: model_config.class_map(),
-            iou_thres: config.config_options.iou_threshold,
-            conf_thres: config.config_options.confidence_threshold,
-            batch_size: config.config_options.batch_size,
-            timeout: 50,
-            model_name: model_config.name.clone(),
-        });
-        for _ in 0..d.workers {
-            let detect_config = Arc::clone(&detect_config);
-            let array_q_r = array_q_r.clone();
-            let export_q_s = export_q_s.clone();
-            let detect_handle = detect_worker(detect_config, array_q_r, export_q_s);
-            detect_handles.push(detect_handle);
+impl Default for DetectOptions {
+    fn default() -> Self {
+        Self {
+            selected_folder: String::new(),
+            model: String::new(),
+            resume_path: None,
+            guess: false,
+            ep: vec![EpConfig {
+                ep: Ep::Cpu,
+                workers: 1,
+                device: "CPU".to_string(),
+                id: "cpu".to_string(),
+            }],
         }
     }
-
-    for _ in 0..4 {
-        let export_q_r = export_q_r.clone();
-        let export_data = Arc::clone(&export_data);
-        let folder_path = folder_path.clone();
-        let checkpoint_counter = Arc::clone(&checkpoint_counter);
-        let export_handle = std::thread::spawn(move || {
-            export_worker(
-                config.config_options.check_point,
-                &checkpoint_counter,
-                &config.config_options.export_format,
-                &folder_path,
-                export_q_r,
-                &export_data,
-            );
-        });
-        export_handles.push(export_handle);
-    }
-
-    let (io_q_s, io_q_r) = bounded(config.config_options.buffer_size);
-
-    let progress_sender_clone = progress_sender.clone();
-
-    match &config.config_options.buffer_path {
-        Some(buffer_path) => {
-            let buffer_path = std::path::PathBuf::from(buffer_path);
-            std::fs::create_dir_all(&buffer_path)?;
-            let buffer_path = std::fs::canonicalize(buffer_path)?;
-
-            let io_handle = std::thread::spawn(move || {
-                for file in file_paths.iter() {
-                    io::io_worker(&buffer_path, file, io_q_s.clone()).unwrap();
-                }
-                drop(io_q_s);
-            });
-
-            io_q_r.iter().par_bridge().for_each(|file| {
-                let array_q_s = array_q_s.clone();
-                media_worker(
-                    file,
-                    imgsz,
-                    config.config_options.iframe_only,
-                    max_frames,
-                    array_q_s,
-                    progress_sender_clone.clone(),
-                );
-            });
-            io_handle.join().unwrap();
-        }
-        None => {
-            file_paths.par_iter().for_each(|file| {
-                let array_q_s = array_q_s.clone();
-                media_worker(
-                    file.clone(),
-                    imgsz,
-                    config.config_options.iframe_only,
-                    max_frames,
-                    array_q_s,
-                    progress_sender_clone.clone(),
-                );
-            });
-        }
-    }
-
-    drop(array_q_s);
-
-    for d_handle in detect_handles {
-        match d_handle.join() {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error joining detect worker: {:?}", e);
-                std::process::exit(1);
-            }
-        }
-    }
-
-    drop(export_q_s);
-
-    for e_handle in export_handles {
-        match e_handle.join() {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error joining export worker: {:?}", e);
-                std::process::exit(1);
-            }
-        }
-    }
-
-    export(
-        &folder_path,
-        export_data,
-        &config.config_options.export_format,
-    )?;
-
-    cleanup_buffer(&config.config_options.buffer_path)?;
-
-    log::info!("Elapsed time: {:?}", start.elapsed());
-    Ok(())
 }
 
-fn cleanup_buffer(buffer_path: &Option<String>) -> Result<()> {
-    if let Some(path) = buffer_path {
-        let path = std::path::PathBuf::from(path);
-        if path.exists() {
-            std::fs::remove_dir_all(path)?;
+impl Default for ConfigOptions {
+    fn default() -> Self {
+        Self {
+            confidence_threshold: 0.2,
+            iou_threshold: 0.45,
+            export_format: ExportFormat::Json,
+            max_frames: Some(3),
+            video_sampling: VideoSampling::Evenly,
+            extensions: MediaExtensions::default(),
+            classifier: None,
+            tiling: None,
+            class_thresholds: BTreeMap::new(),
+            include_classes: Vec::new(),
+            exclude_classes: Vec::new(),
+            camtrap_dp: CamtrapDpOptions::default(),
+            sequence: SequenceOptions::default(),
+            iframe_only: true,
+            check_point: 100,
+            buffer_path: None,
+            buffer_size: 20,
+            batch_size: 2,
         }
     }
-    Ok(())
-}
-
-fn resume_from_checkpoint<'a>(
-    checkpoint_path: &str,
-    all_files: &'a mut HashSet<FileItem>,
-    export_data: &Arc<Mutex<Vec<ExportFrame>>>,
-) -> Result<&'a mut HashSet<FileItem>> {
-    let checkpoint = Path::new(checkpoint_path);
-    if !checkpoint.exists() {
-        log::error!("Checkpoint file does not exist");
-        return Err(anyhow::anyhow!("Checkpoint file does not exist"));
-    }
-    if !checkpoint.is_file() {
-        log::error!("Checkpoint path is not a file");
-        return Err(anyhow::anyhow!("Checkpoint path is not a file"));
-    }
-    match checkpoint.extension() {
-        Some(ext) => {
-            let ext = ext.to_str().unwrap();
-            if ext != "json" && ext != "csv" {
-                log::error!("Invalid checkpoint file extension: {}", ext);
-                return Err(anyhow::anyhow!(
-                    "Invalid checkpoint file extension: {}",
-                    ext
-                ));
-            } else {
-                let frames;
-                if ext == "json" {
-                    let json = std::fs::read_to_string(checkpoint)?;
-                    frames = serde_json::from_str(&json)?;
-                } else {
-                    frames = parse_export_csv(checkpoint)?;
-                }
-                let mut file_frame_count = HashMap::new();
-                let mut file_total_frames = HashMap::new();
-                for f in &frames {
-                    let file = &f.file;
-                    let count = file_frame_count.entry(file.clone()).or_insert(0);
-                    *count += 1;
-                    file_total_frames
-                        .entry(file.clone())
-                        .or_insert(f.total_frames);
-
-                    if let Some(total_frames) = file_total_frames.get(&file) {
-                        if let Some(frame_count) = file_frame_count.get(&file) {
-                            if total_frames == frame_count {
-                                all_files.remove(&file);
-                            }
-                        }
-                    }
-                }
-                export_data.lock().unwrap().extend_from_slice(&frames);
-                Ok(all_files)
-            }
-        }
-        None => {
-            log::error!("Invalid checkpoint file extension");
-            return Err(anyhow::anyhow!("Invalid checkpoint file extension"));
-        }
-    }
-}
-
-#[tauri::command]
-async fn list_devices(app: AppHandle) {
-    if let Ok(devices) = get_devices() {
-        app.emit("devices", devices).unwrap();
-    }
 }
 
-#[tauri::command]
-fn calculate_md5(file_path: String) -> Result<String, String> {
-    let mut file = File::open(&file_path).map_err(|e| e.to_string())?;
-    let mut hasher = Md5::new();
-    let _ = std::io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
-    let hash = hasher.finalize();
-
-    Ok(format!("{:x}", hash))
-}
-
-#[derive(Serialize, Clone)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct DownloadProgress {
-    model_name: String,
-    progress: f32,
-    finished: bool,
-    error: Option<String>,
+pub struct Config {
+    pub detect_options: DetectOptions,
+    pub config_options: ConfigOptions,
 }
 
-#[tauri::command]
-async fn download_model(
-    url: String,
-    destination: String,
-    model_name: String,
-    app: AppHandle,
-    md5: String,
-) -> Result<(), String> {
-    tokio::spawn(async move {
-        log::info!("Downloading model from {}", url);
-        let client = reqwest::Client::new();
-
-        let resp = match client.head(&url).send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                log::error!("Could not connect to server: {}", e);
-                let _ = app.emit(
-                    "download-progress",
-                    DownloadProgress {
-                        model_name: model_name.clone(),
-                        progress: 0.0,
-                        finished: true,
-                        error: Some(format!("Could not connect to server: {}", e)),
-                    },
-                );
-                return;
-            }
-        };
-
-        let mut total_size = resp.content_length().unwrap_or(0);
-
-        let resp = match client.get(&url).send().await {
-            Ok(resp) => {
-                if !resp.status().is_success() {
-                    let _ = app.emit(
-                        "download-progress",
-                        DownloadProgress {
-                            model_name: model_name.clone(),
-                            progress: 0.0,
-                            finished: true,
-                            error: Some(format!("Request failed: {}", resp.status())),
-                        },
-                    );
-                    return;
-                }
-                log::info!("Download started");
-                if let Some(content_length) = resp.headers().get("content-length") {
-                    if let Ok(content_length) = content_length.to_str() {
-                        if let Ok(content_length) = content_length.parse::<u64>() {
-                            total_size = content_length;
-                        }
-                    }
-                }
-                resp
-            }
-            Err(e) => {
-                let _ = app.emit(
-                    "download-progress",
-                    DownloadProgress {
-                        model_name: model_name.clone(),
-                        progress: 0.0,
-                        finished: true,
-                        error: Some(format!("Download failed: {}", e)),
-                    },
-                );
-                return;
-            }
-        };
-
-        let mut file = match tokio::fs::File::create(&destination).await {
-            Ok(file) => file,
-            Err(e) => {
-                let _ = app.emit(
-                    "download-progress",
-                    DownloadProgress {
-                        model_name: model_name.clone(),
-                        progress: 0.0,
-                        finished: true,
-                        error: Some(format!("Create file failed: {}", e)),
-                    },
-                );
-                return;
-            }
-        };
-
-        let mut downloaded: u64 = 0;
-        let mut stream = resp.bytes_stream();
-        let mut hasher = Md5::new();
-
-        let mut last_update = Instant::now();
-        let update_interval = Duration::from_millis(250);
-
-        while let Some(item) = stream.next().await {
-            let chunk = match item {
-                Ok(chunk) => chunk,
-                Err(e) => {
-                    let _ = app.emit(
-                        "download-progress",
-                        DownloadProgress {
-                            model_name: model_name.clone(),
-                            progress: 0.0,
-                            finished: true,
-                            error: Some(format!("Download failed: {}", e)),
-                        },
-                    );
-                    return;
-                }
-            };
-
-            // Update MD5 hash with the chunk
-            hasher.update(&chunk);
-
-            if let Err(e) = tokio::io::copy(&mut chunk.as_ref(), &mut file).await {
-                let _ = app.emit(
-                    "download-progress",
-                    DownloadProgress {
-                        model_name: model_name.clone(),
-                        progress: 0.0,
-                        finished: true,
-                        error: Some(format!("Copy file failed: {}", e)),
-                    },
-                );
-                return;
-            }
-
-            downloaded += chunk.len() as u64;
-
-            if total_size > 0 {
-                let progress = (downloaded as f32 / total_size as f32) * 100.0;
-                let now = Instant::now();
-                if now.duration_since(last_update) >= update_interval {
-                    let _ = app.emit(
-                        "download-progress",
-                        DownloadProgress {
-                            model_name: model_name.clone(),
-                            progress,
-                            finished: false,
-                            error: None,
-                        },
-                    );
-                    last_update = now;
-                }
-            }
-        }
-
-        // Verify MD5 checksum
-        let calculated_md5 = format!("{:x}", hasher.finalize());
-        if !md5.is_empty() && calculated_md5 != md5.to_lowercase() {
-            log::error!(
-                "MD5 checksum verification failed. Expected: {}, Got: {}",
-                md5,
-                calculated_md5
-            );
-            let _ = app.emit(
-                "download-progress",
-                DownloadProgress {
-                    model_name: model_name.clone(),
-                    progress: 100.0,
-                    finished: true,
-                    error: Some(format!(
-                        "MD5 checksum verification failed. Expected: {}, Got: {}",
-                        md5, calculated_md5
-                    )),
-                },
-            );
-
-            // Delete the corrupted file
-            if let Err(e) = tokio::fs::remove_file(&destination).await {
-                log::error!("Failed to delete corrupted file: {}", e);
-            }
-            return;
-        }
-
-        log::info!("Download completed and MD5 checksum verified successfully");
-        let _ = app.emit(
-            "download-progress",
-            DownloadProgress {
-                model_name,
-                progress: 100.0,
-                finished: true,
-                error: None,
-            },
-        );
-    });
-
-    Ok(())
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    /// MegaDetector batch output, written next to `result.json`
+    MegaDetector,
+    /// COCO Camera Traps, written next to `result.json`
+    CocoCameraTraps,
+    /// Camtrap DP package in `camtrap-dp/`, written next to `result.json`
+    CamtrapDp,
 }
 
-#[tauri::command]
-async fn process_media(app: AppHandle, config: Config) {
-    let (progress_sender, progress_receiver) = crossbeam_channel::bounded(5);
-
-    let total_files;
-
-    match crate::utils::index_files_and_folders(&PathBuf::from(
-        &config.detect_options.selected_folder,
-    )) {
-        Ok(files) => {
-            total_files = files.len();
-        }
-        Err(e) => {
-            log::error!("{}", e);
-            app.emit("detect-error", e.to_string()).unwrap();
-            return;
+impl ExportFormat {
+    /// Name of the result file written into the media folder.
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "result.json",
+            ExportFormat::Csv => "result.csv",
+            ExportFormat::MegaDetector => "result.megadetector.json",
+            ExportFormat::CocoCameraTraps => "result.coco.json",
+            ExportFormat::CamtrapDp => "camtrap-dp/datapackage.json",
         }
     }
 
-    let app_clone = app.clone();
-
-    let progress_thread = std::thread::spawn(move || {
-        let mut progress = 0.0;
-        for _ in progress_receiver.iter() {
-            progress += 1.0 / total_files as f32 * 100.0;
-            app_clone
-                .emit("detect-progress", progress as usize)
-                .unwrap();
-        }
-    });
-
-    match process(config, progress_sender).await {
-        Ok(_) => {
-            app.emit("detect-complete", 1).unwrap();
-        }
-        Err(e) => {
-            app.emit("detect-error", e.to_string()).unwrap();
-            log::error!("Error processing: {}", e);
+    /// Result file other tools read back, like the organizer. Interchange
+    /// formats lack fields it needs, so `result.json` is written too.
+    pub fn native(&self) -> ExportFormat {
+        match self {
+            ExportFormat::Csv => ExportFormat::Csv,
+            _ => ExportFormat::Json,
         }
     }
-    progress_thread.join().unwrap();
-}
-
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    tauri::Builder::default()
-        .plugin(tauri_plugin_store::Builder::default().build())
-        .plugin(
-            tauri_plugin_log::Builder::new()
-                .level(log::LevelFilter::Info)
-                .filter(|metadata| metadata.target() != "hyper")
-                .build(),
-        )
-        .plugin(tauri_plugin_shell::init())
-        .plugin(tauri_plugin_fs::init())
-        .plugin(tauri_plugin_dialog::init())
-        .plugin(tauri_plugin_opener::init())
-        .invoke_handler(tauri::generate_handler![
-            process_media,
-            list_devices,
-            download_model,
-            calculate_md5,
-        ])
-        .setup(|app| {
-            let _ = app.store("store.json")?;
-            Ok(())
-        })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
 }
@@ -1,25 +1,28 @@
+use std::collections::HashMap;
 use std::fs::{metadata, File};
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::str;
+use std::str::FromStr;
 use std::thread;
 use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, Local};
+use chrono::{DateTime, FixedOffset, Local};
 use crossbeam_channel::Sender;
 use fast_image_resize::{ResizeAlg, ResizeOptions, Resizer};
 use ffmpeg_sidecar::command::FfmpegCommand;
 use ffmpeg_sidecar::event::{FfmpegEvent, LogLevel};
 use ffmpeg_sidecar::ffprobe::ffprobe_path;
 use ffmpeg_sidecar::iter::FfmpegIterator;
-use image::{DynamicImage, GenericImageView, ImageReader};
+use image::metadata::Orientation;
+use image::{DynamicImage, GenericImageView, ImageReader, RgbImage};
 use jpeg_decoder::Decoder;
 use log::{debug, error, warn};
 use ndarray::{s, Array3, Dim};
-use nom_exif::{Exif, ExifIter, ExifTag, MediaParser, MediaSource};
+use nom_exif::{Exif, ExifIter, ExifTag, MediaParser, MediaSource, TrackInfo, TrackInfoTag};
 use nshare::AsNdarray3Mut;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::utils::{sample_evenly, FileItem};
@@ -38,6 +41,128 @@ pub enum MediaError {
 
     #[error("Ffmpeg error when decoding {1}: {0}")]
     FfmpegError(String, String),
+
+    #[error("Frame queue closed, detect workers stopped")]
+    QueueClosed,
+}
+
+/// How frames are picked from a video.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(tag = "mode", rename_all = "camelCase")]
+pub enum VideoSampling {
+    /// `max_frames` frames spread evenly over the whole video.
+    #[default]
+    Evenly,
+    /// `fps` frames per second of video.
+    Fps { fps: f32 },
+    /// One frame every `seconds` seconds of video.
+    Interval { seconds: f32 },
+}
+
+impl VideoSampling {
+    /// Output rate of ffmpeg's fps filter, `None` when sampling evenly.
+    pub fn fps(&self) -> Option<f32> {
+        match self {
+            VideoSampling::Evenly => None,
+            VideoSampling::Fps { fps } => Some(*fps),
+            VideoSampling::Interval { seconds } => Some(1.0 / seconds),
+        }
+    }
+}
+
+/// File extensions picked up when indexing a folder, compared case-insensitively.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct MediaExtensions {
+    pub images: Vec<String>,
+    pub videos: Vec<String>,
+}
+
+impl Default for MediaExtensions {
+    fn default() -> Self {
+        let to_strings = |extensions: &[&str]| extensions.iter().map(|e| e.to_string()).collect();
+        Self {
+            images: to_strings(&[
+                "jpg", "jpeg", "png", "tif", "tiff", "webp", "heic", "heif", "dng",
+            ]),
+            videos: to_strings(&["mp4", "avi", "mkv", "mov"]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    Video,
+}
+
+impl MediaExtensions {
+    pub fn kind(&self, path: &Path) -> Option<MediaKind> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        let matches = |extensions: &Vec<String>| {
+            extensions
+                .iter()
+                .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&extension))
+        };
+        if matches(&self.images) {
+            Some(MediaKind::Image)
+        } else if matches(&self.videos) {
+            Some(MediaKind::Video)
+        } else {
+            None
+        }
+    }
+}
+
+/// Where a frame's `shoot_time` was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeSource {
+    /// EXIF DateTimeOriginal or ModifyDate of an image
+    Exif,
+    /// Creation time tags of a video container or track
+    Container,
+    /// File modification time, only used when the file has no embedded time
+    FileSystem,
+}
+
+impl TimeSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeSource::Exif => "exif",
+            TimeSource::Container => "container",
+            TimeSource::FileSystem => "filesystem",
+        }
+    }
+}
+
+impl FromStr for TimeSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exif" => Ok(TimeSource::Exif),
+            "container" => Ok(TimeSource::Container),
+            "filesystem" => Ok(TimeSource::FileSystem),
+            _ => Err(anyhow!("Unknown time source: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaConfig {
+    pub imgsz: usize,
+    /// Letterbox color in 0–1 RGB
+    pub pad_color: [f32; 3],
+    /// Keep decoded stills on the frame for the classifier and tiling
+    pub keep_original: bool,
+    /// Decode video frames at native resolution and keep them like stills,
+    /// so they can be tiled. Letterboxing moves from ffmpeg to the resizer
+    pub full_resolution_video: bool,
+    pub iframe: bool,
+    pub max_frames: Option<usize>,
+    pub sampling: VideoSampling,
+    pub extensions: MediaExtensions,
 }
 
 pub struct Frame {
@@ -50,7 +175,18 @@ pub struct Frame {
     pub frame_index: usize,
     pub total_frames: usize,
     pub shoot_time: Option<DateTime<Local>>,
+    pub shoot_time_source: Option<TimeSource>,
+    /// EXIF orientation applied before preprocessing, `width`, `height` and
+    /// bboxes are in the displayed orientation
+    pub orientation: Option<u16>,
     pub iframe: bool,
+    /// Presentation time of a video frame in seconds from the start of the video
+    pub timestamp: Option<f64>,
+    pub video_duration: Option<f64>,
+    pub video_fps: Option<f64>,
+    /// Full resolution still, or video frame when tiling, in the displayed
+    /// orientation. Only kept for the classifier and tiling
+    pub original: Option<RgbImage>,
 }
 
 pub struct ErrFile {
@@ -65,30 +201,39 @@ pub enum ArrayItem {
 
 pub fn media_worker(
     file: FileItem,
-    imgsz: usize,
-    iframe: bool,
-    max_frames: Option<usize>,
+    config: &MediaConfig,
     array_q_s: Sender<ArrayItem>,
     progress_sender: Sender<usize>,
 ) {
     let mut parser = MediaParser::new();
     let mut resizer = Resizer::new();
-    if let Some(extension) = file.file_path.extension() {
-        let array_q_s = array_q_s.clone();
-        match extension.to_str().unwrap().to_lowercase().as_str() {
-            "jpg" | "jpeg" | "png" => {
-                process_image(&file, imgsz, &mut parser, &mut resizer, array_q_s).unwrap();
+    if let Some(kind) = config.extensions.kind(&file.file_path) {
+        let result = match kind {
+            MediaKind::Image => {
+                process_image(&file, config, &mut parser, &mut resizer, array_q_s.clone())
             }
-            "mp4" | "avi" | "mkv" | "mov" => {
-                process_video(&file, imgsz, iframe, max_frames, array_q_s).unwrap();
+            MediaKind::Video => {
+                process_video(&file, config, &mut parser, &mut resizer, array_q_s.clone())
             }
-            _ => (),
+        };
+        if let Err(error) = result {
+            error!(
+                "Failed to process {}: {:#}",
+                file.file_path.display(),
+                error
+            );
+            // Fails only if the detect workers are gone, then there is no one to report to
+            let _ = array_q_s.send(ArrayItem::ErrFile(ErrFile {
+                file: file.clone(),
+                error,
+            }));
         }
         if &file.file_path != &file.tmp_path {
-            remove_file_with_retries(&file.tmp_path, 3, Duration::from_secs(1))
-                .expect("Failed to remove file");
+            if let Err(e) = remove_file_with_retries(&file.tmp_path, 3, Duration::from_secs(1)) {
+                warn!("Failed to remove buffered file: {}", e);
+            }
         }
-        progress_sender.send(1).expect("Send progress failed");
+        let _ = progress_sender.send(1);
     }
 }
 
@@ -121,12 +266,24 @@ fn remove_file_with_retries(file_path: &PathBuf, max_retries: u32, delay: Durati
 }
 
 fn decode_image(file: &FileItem) -> Result<DynamicImage> {
+    let extension = file
+        .file_path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    // The image crate has no HEIF or RAW decoder, go straight to ffmpeg
+    if matches!(extension.as_str(), "heic" | "heif" | "dng") {
+        return decode_image_ffmpeg(&file.tmp_path);
+    }
+
     let img = match ImageReader::open(file.tmp_path.as_path())
+        .map_err(MediaError::IoError)?
+        .with_guessed_format()
         .map_err(MediaError::IoError)?
         .decode()
     {
         Ok(img) => img,
-        Err(_e) => {
+        Err(_e) if matches!(extension.as_str(), "jpg" | "jpeg") => {
             warn!(
                 "Failed to decode image with ImageReader. Trying jpeg_decoder. {:?}",
                 _e
@@ -134,35 +291,82 @@ fn decode_image(file: &FileItem) -> Result<DynamicImage> {
             let img_reader = File::open(file.tmp_path.as_path()).map_err(MediaError::IoError)?;
             let mut decoder = Decoder::new(BufReader::new(img_reader));
             let pixels = decoder.decode().map_err(MediaError::ImageDecodeError)?;
+            let info = decoder.info().context("Missing jpeg image info")?;
             let img = DynamicImage::ImageRgb8(
-                image::ImageBuffer::from_raw(
-                    decoder.info().unwrap().width as u32,
-                    decoder.info().unwrap().height as u32,
-                    pixels,
-                )
-                .unwrap(),
+                image::ImageBuffer::from_raw(info.width as u32, info.height as u32, pixels)
+                    .context("Decoded jpeg size does not match its header")?,
             );
             img
         }
+        Err(_e) => {
+            warn!(
+                "Failed to decode image with ImageReader. Trying ffmpeg. {:?}",
+                _e
+            );
+            decode_image_ffmpeg(&file.tmp_path)?
+        }
     };
     Ok(img)
 }
 
+/// Decode the first frame of a still with ffmpeg. Rotation is left to the
+/// EXIF orientation handling so every format is rotated the same way.
+fn decode_image_ffmpeg(path: &Path) -> Result<DynamicImage> {
+    let iter = FfmpegCommand::new()
+        .args(["-noautorotate"])
+        .input(path.to_string_lossy())
+        .args(["-frames:v", "1", "-f", "rawvideo", "-pix_fmt", "rgb24"])
+        .output("-")
+        .spawn()?
+        .iter()?;
+
+    let mut ffmpeg_error = Vec::new();
+    for event in iter {
+        match event {
+            FfmpegEvent::Error(e) | FfmpegEvent::Log(LogLevel::Error, e) => {
+                ffmpeg_error.push(e);
+            }
+            FfmpegEvent::OutputFrame(f) => {
+                let img = image::RgbImage::from_raw(f.width, f.height, f.data)
+                    .context("Unexpected ffmpeg frame size")?;
+                return Ok(DynamicImage::ImageRgb8(img));
+            }
+            _ => (),
+        }
+    }
+
+    Err(MediaError::FfmpegError(ffmpeg_error.join("; "), path.display().to_string()).into())
+}
+
 pub fn process_image(
     file: &FileItem,
-    imgsz: usize,
+    config: &MediaConfig,
     parser: &mut MediaParser,
     resizer: &mut Resizer,
     array_q_s: Sender<ArrayItem>,
 ) -> Result<()> {
     let frame_data = match decode_image(file) {
-        Ok(img) => {
-            let (img_array, pad_w, pad_h, ratio) = resize_with_pad(&img, imgsz as u32, resizer)?;
-            let shoot_time: Option<DateTime<Local>> =
-                match get_image_date(parser, file.tmp_path.as_path()) {
-                    Ok(shoot_time) => Some(shoot_time),
-                    Err(_e) => None,
-                };
+        Ok(mut img) => {
+            let exif = match read_exif(parser, file.tmp_path.as_path()) {
+                Ok(exif) => Some(exif),
+                Err(_e) => None,
+            };
+            let shoot_time: Option<DateTime<Local>> = match &exif {
+                Some(exif) => get_image_date(exif).ok(),
+                None => None,
+            };
+            let shoot_time_source = shoot_time.map(|_| TimeSource::Exif);
+
+            // Rotate to the displayed orientation so bboxes line up with image viewers
+            let orientation = exif.as_ref().and_then(get_image_orientation);
+            if let Some(exif_orientation) = orientation {
+                if let Some(transform) = Orientation::from_exif(exif_orientation as u8) {
+                    img.apply_orientation(transform);
+                }
+            }
+
+            let (img_array, pad_w, pad_h, ratio) =
+                resize_with_pad(&img, config.imgsz as u32, config.pad_color, resizer)?;
             let frame_data = Frame {
                 data: img_array,
                 file: file.clone(),
@@ -173,7 +377,13 @@ pub fn process_image(
                 frame_index: 0,
                 total_frames: 1,
                 shoot_time,
+                shoot_time_source,
+                orientation,
                 iframe: false,
+                timestamp: None,
+                video_duration: None,
+                video_fps: None,
+                original: config.keep_original.then(|| img.to_rgb8()),
             };
 
             ArrayItem::Frame(frame_data)
@@ -183,7 +393,9 @@ pub fn process_image(
             error,
         }),
     };
-    array_q_s.send(frame_data).expect("Send image frame failed");
+    array_q_s
+        .send(frame_data)
+        .map_err(|_| MediaError::QueueClosed)?;
 
     Ok(())
 }
@@ -191,6 +403,7 @@ pub fn process_image(
 fn resize_with_pad(
     img: &DynamicImage,
     imgsz: u32,
+    pad_color: [f32; 3],
     resizer: &mut Resizer,
 ) -> Result<(Array3<f32>, usize, usize, f32)> {
     // Get the dimensions of the original image
@@ -215,7 +428,7 @@ fn resize_with_pad(
 
     resizer
         .resize(img, &mut resized_img, &resize_option)
-        .unwrap();
+        .context("Failed to resize image")?;
 
     let mut resized_img = resized_img.to_rgb8();
 
@@ -224,7 +437,10 @@ fn resize_with_pad(
     let pad_width = (imgsz - resized_width) / 2;
     let pad_height = (imgsz - resized_height) / 2;
 
-    let mut padded_array = Array3::<f32>::from_elem(Dim([3, imgsz as usize, imgsz as usize]), 0.44);
+    let mut padded_array = Array3::<f32>::zeros(Dim([3, imgsz as usize, imgsz as usize]));
+    for (c, &value) in pad_color.iter().enumerate() {
+        padded_array.slice_mut(s![c, .., ..]).fill(value);
+    }
 
     padded_array
         .slice_mut(s![
@@ -239,236 +455,655 @@ fn resize_with_pad(
 
 pub fn process_video(
     file: &FileItem,
-    imgsz: usize,
-    iframe: bool,
-    max_frames: Option<usize>,
+    config: &MediaConfig,
+    parser: &mut MediaParser,
+    resizer: &mut Resizer,
     array_q_s: Sender<ArrayItem>,
 ) -> Result<()> {
     let video_path = file.tmp_path.to_string_lossy();
 
-    let (orig_w, orig_h) = match get_video_dimensions(&video_path) {
-        Ok(dim) => dim,
+    let info = match probe_video(&video_path) {
+        Ok(info) => info,
         Err(e) => {
-            let error = anyhow!(e).context("Failed to get video dimensions");
-            log::error!("{}", error);
+            let error = e.context("Failed to probe video");
+            log::error!("{:#}", error);
             let frame_data = ArrayItem::ErrFile(ErrFile {
                 file: file.clone(),
                 error,
             });
             array_q_s
                 .send(frame_data)
-                .context("Failed to send dimension error")?;
+                .map_err(|_| MediaError::QueueClosed)?;
             return Ok(());
         }
     };
 
-    let input = create_ffmpeg_iter(&video_path, imgsz, iframe)?;
+    // Decide which decoded frames to keep before decoding, so frames can be
+    // sent on as they come out of ffmpeg instead of holding the whole video
+    let fps = config.sampling.fps();
+    // The fps filter fills gaps by repeating frames, so time based sampling decodes all frames
+    let iframe = config.iframe && fps.is_none();
+    let selected = match fps {
+        Some(fps) => {
+            let duration = info.duration.context("Unknown video duration")?;
+            // Rounded down, a missing last frame is better than a file that never looks complete
+            let expected = ((duration * fps as f64).floor() as usize).max(1);
+            (0..expected).collect()
+        }
+        None => {
+            let decoded_frames = if iframe {
+                info.keyframe_count
+            } else {
+                info.frame_count
+            };
+            let candidates: Vec<usize> = (0..decoded_frames).collect();
+            sample_evenly(
+                &candidates,
+                config
+                    .max_frames
+                    .unwrap_or(decoded_frames)
+                    .min(decoded_frames),
+            )
+        }
+    };
+
+    // Nothing would be sent and the file would never count as processed
+    if selected.is_empty() {
+        return Err(anyhow!("No frames to sample, ffprobe found no packets"));
+    }
 
-    handle_ffmpeg_output(
-        input, array_q_s, imgsz, file, max_frames, orig_w, orig_h, iframe,
-    )?;
+    let start_time = match get_video_date(file, &info, parser) {
+        Ok(start_time) => Some(start_time),
+        Err(e) => {
+            warn!("No creation time for {}: {:#}", file.file_path.display(), e);
+            None
+        }
+    };
+
+    let input = create_ffmpeg_iter(&video_path, config, iframe, fps)?;
+    let selection = FrameSelection {
+        info: &info,
+        selected,
+        start_time,
+        iframe,
+        fps,
+    };
+    handle_ffmpeg_output(input, array_q_s, config, file, &selection, resizer)?;
 
     Ok(())
 }
 
-fn get_video_dimensions(video_path: &str) -> Result<(usize, usize)> {
+/// Stream layout of a video as reported by ffprobe, read from packet headers
+/// without decoding.
+struct VideoInfo {
+    width: usize,
+    height: usize,
+    frame_count: usize,
+    keyframe_count: usize,
+    duration: Option<f64>,
+    fps: Option<f64>,
+    /// Presentation times of all frames relative to the stream start, in display order
+    frame_times: Vec<f64>,
+    keyframe_times: Vec<f64>,
+    /// QuickTime `com.apple.quicktime.creationdate`, local time with its offset
+    creation_date: Option<DateTime<FixedOffset>>,
+    /// `creation_time` tag of the container or stream, in UTC
+    creation_time: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    packets: Vec<ProbePacket>,
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ProbePacket {
+    pts_time: Option<String>,
+    #[serde(default)]
+    flags: String,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    width: Option<usize>,
+    height: Option<usize>,
+    duration: Option<String>,
+    start_time: Option<String>,
+    avg_frame_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// Parse a creation time tag. Cameras without a clock write the container
+/// epoch (1904 or 1970), those are treated as missing.
+fn parse_creation_time(value: &str) -> Option<DateTime<FixedOffset>> {
+    let time = DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%z"))
+        .ok()?;
+    if time.timestamp() <= 0 {
+        None
+    } else {
+        Some(time)
+    }
+}
+
+/// Parse an ffprobe rational such as `30000/1001`, `0/0` means unknown.
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/')?;
+    let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+    if num > 0.0 && den > 0.0 {
+        Some(num / den)
+    } else {
+        None
+    }
+}
+
+fn ffprobe_command() -> Command {
+    #[allow(unused_mut)]
     let mut command = Command::new(ffprobe_path());
 
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    command
+}
+
+fn probe_video(video_path: &str) -> Result<VideoInfo> {
+    let mut command = ffprobe_command();
+
     command.args([
         "-v",
         "error",
         "-select_streams",
         "v:0",
         "-show_entries",
-        "stream=width,height",
+        "stream=width,height,duration,start_time,avg_frame_rate:stream_tags=creation_time:packet=pts_time,flags:format=duration:format_tags=creation_time,com.apple.quicktime.creationdate",
         "-of",
-        "csv=s=x:p=0",
+        "json",
         video_path,
     ]);
 
-    #[cfg(windows)]
-    {
-        use std::os::windows::process::CommandExt;
-        const CREATE_NO_WINDOW: u32 = 0x08000000;
-        command.creation_flags(CREATE_NO_WINDOW);
-    }
-
     let output = command
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .output()?;
 
-    let dimensions = str::from_utf8(&output.stdout)?;
-    let parts: Vec<&str> = dimensions.trim().split('x').collect();
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ffprobe failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
 
-    if parts.len() == 2 {
-        let width = parts[0].parse::<usize>()?;
-        let height = parts[1].parse::<usize>()?;
-        Ok((width, height))
-    } else {
-        Err(anyhow!("Invalid video dimensions: {}", dimensions))
+    let probe: ProbeOutput = serde_json::from_slice(&output.stdout)?;
+    let stream = probe.streams.first().context("No video stream found")?;
+    let (width, height) = match (stream.width, stream.height) {
+        (Some(width), Some(height)) => (width, height),
+        _ => return Err(anyhow!("Invalid video dimensions")),
+    };
+
+    let frame_count = probe.packets.len();
+    let keyframe_count = probe
+        .packets
+        .iter()
+        .filter(|packet| packet.flags.starts_with('K'))
+        .count();
+
+    // Not every container has a stream duration (e.g. mkv), fall back to the container's
+    let duration = stream
+        .duration
+        .as_ref()
+        .or(probe
+            .format
+            .as_ref()
+            .and_then(|format| format.duration.as_ref()))
+        .and_then(|duration| duration.parse::<f64>().ok());
+    let fps = stream.avg_frame_rate.as_deref().and_then(parse_frame_rate);
+
+    // Packets are listed in decode order, ffmpeg outputs frames in presentation order
+    let packet_time = |packet: &ProbePacket| {
+        packet
+            .pts_time
+            .as_ref()
+            .and_then(|pts| pts.parse::<f64>().ok())
+    };
+    let mut frame_times: Vec<f64> = probe.packets.iter().filter_map(packet_time).collect();
+    let mut keyframe_times: Vec<f64> = probe
+        .packets
+        .iter()
+        .filter(|packet| packet.flags.starts_with('K'))
+        .filter_map(packet_time)
+        .collect();
+    frame_times.sort_by(f64::total_cmp);
+    keyframe_times.sort_by(f64::total_cmp);
+    let start_time = stream
+        .start_time
+        .as_ref()
+        .and_then(|start| start.parse::<f64>().ok())
+        .or(frame_times.first().copied())
+        .unwrap_or(0.0);
+    for time in frame_times.iter_mut().chain(keyframe_times.iter_mut()) {
+        *time = (*time - start_time).max(0.0);
     }
+
+    let format_tags = probe.format.as_ref().map(|format| &format.tags);
+    let creation_date = format_tags
+        .and_then(|tags| tags.get("com.apple.quicktime.creationdate"))
+        .and_then(|value| parse_creation_time(value));
+    let creation_time = format_tags
+        .and_then(|tags| tags.get("creation_time"))
+        .or(stream.tags.get("creation_time"))
+        .and_then(|value| parse_creation_time(value));
+
+    Ok(VideoInfo {
+        width,
+        height,
+        frame_count,
+        keyframe_count,
+        duration,
+        fps,
+        frame_times,
+        keyframe_times,
+        creation_date,
+        creation_time,
+    })
 }
 
-fn create_ffmpeg_iter(video_path: &str, imgsz: usize, iframe: bool) -> Result<FfmpegIterator> {
+fn create_ffmpeg_iter(
+    video_path: &str,
+    config: &MediaConfig,
+    iframe: bool,
+    fps: Option<f32>,
+) -> Result<FfmpegIterator> {
     let mut ffmpeg_command = FfmpegCommand::new();
     if iframe {
         ffmpeg_command.args(["-skip_frame", "nokey"]);
     }
+    let mut filters = Vec::new();
+    if let Some(fps) = fps {
+        // Sample before scaling so dropped frames are never scaled
+        filters.push(format!("fps={}", fps));
+    }
+    if !config.full_resolution_video {
+        let imgsz = config.imgsz;
+        let [r, g, b] = config
+            .pad_color
+            .map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+        filters.push(format!(
+            "scale=w={}:h={}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2:color=0x{:02x}{:02x}{:02x}",
+            imgsz, imgsz, imgsz, imgsz, r, g, b
+        ));
+    }
+    ffmpeg_command.input(video_path).arg("-an");
+    if !filters.is_empty() {
+        ffmpeg_command.args(["-vf", &filters.join(",")]);
+    }
     let iter = ffmpeg_command
-        .input(video_path)
-        .args(&[
-            "-an",
-            "-vf",
-            &format!(
-                "scale=w={}:h={}:force_original_aspect_ratio=decrease,pad={}:{}:(ow-iw)/2:(oh-ih)/2",
-                imgsz, imgsz, imgsz, imgsz
-            ),
-            "-f",
-            "rawvideo",
-            "-pix_fmt",
-            "rgb24",
-            "-vsync",
-            "vfr",
-        ])
+        .args(["-f", "rawvideo", "-pix_fmt", "rgb24", "-vsync", "vfr"])
         .output("-")
         .spawn()?
         .iter()?;
     Ok(iter)
 }
 
+/// Frames of a video to keep and how ffmpeg outputs them.
+struct FrameSelection<'a> {
+    info: &'a VideoInfo,
+    /// ffmpeg output frame numbers to keep, ascending
+    selected: Vec<usize>,
+    start_time: Option<(DateTime<Local>, TimeSource)>,
+    /// Only key frames are decoded
+    iframe: bool,
+    /// Rate of the fps filter, `None` when every decoded frame is output
+    fps: Option<f32>,
+}
+
+impl FrameSelection<'_> {
+    /// Presentation time of ffmpeg output frame `frame_num` in seconds.
+    fn timestamp(&self, frame_num: usize) -> Option<f64> {
+        match self.fps {
+            // The fps filter puts output frame n at n / fps from the first frame
+            Some(fps) => Some(frame_num as f64 / fps as f64),
+            // With -vsync vfr output frame n is the n-th decoded frame
+            None if self.iframe => self.info.keyframe_times.get(frame_num).copied(),
+            None => self.info.frame_times.get(frame_num).copied(),
+        }
+    }
+}
+
 fn handle_ffmpeg_output(
     input: FfmpegIterator,
     s: Sender<ArrayItem>,
-    imgsz: usize,
+    config: &MediaConfig,
     file: &FileItem,
-    max_frames: Option<usize>,
-    orig_w: usize,
-    orig_h: usize,
-    iframe: bool,
+    selection: &FrameSelection,
+    resizer: &mut Resizer,
 ) -> Result<()> {
+    let imgsz = config.imgsz;
     let file_path = file.file_path.to_string_lossy().into_owned();
+    let info = selection.info;
+    let selected = &selection.selected;
+    let (orig_w, orig_h) = (info.width, info.height);
+    let (shoot_time, shoot_time_source) = match selection.start_time {
+        Some((shoot_time, source)) => (Some(shoot_time), Some(source)),
+        None => (None, None),
+    };
+
+    //calculate ratio and padding
+    let ratio = orig_w.max(orig_h) as f32 / imgsz as f32;
+    let pad = (orig_w as f32 - orig_h as f32).abs() / 2.0 / ratio;
+    let padding = if orig_w > orig_h {
+        (0, pad as usize)
+    } else {
+        (pad as usize, 0)
+    };
 
-    let mut frames = Vec::new();
+    let mut decoded = 0;
+    let mut sent = 0;
+    // Index into `selected` of the next frame to keep
+    let mut next = 0;
+    // The last frame is held back to record how many frames were actually sent
+    let mut pending: Option<Frame> = None;
     let mut ffmpeg_error = Vec::new();
     for event in input {
         match event {
             FfmpegEvent::Error(e) | FfmpegEvent::Log(LogLevel::Error, e) => {
                 ffmpeg_error.push(e);
             }
-            FfmpegEvent::OutputFrame(frame) => {
-                frames.push(frame);
+            FfmpegEvent::OutputFrame(f) => {
+                decoded += 1;
+                let frame_num = f.frame_num as usize;
+                if next >= selected.len() || frame_num < selected[next] {
+                    continue;
+                }
+                // A selected frame ffmpeg didn't output is replaced by the next one it does
+                while next < selected.len() && selected[next] <= frame_num {
+                    next += 1;
+                }
+                let (data, width, height, padding, ratio, original) =
+                    if config.full_resolution_video {
+                        // ffmpeg outputs the displayed size, which differs from
+                        // ffprobe's for rotated videos
+                        let img = RgbImage::from_raw(f.width, f.height, f.data)
+                            .context("Unexpected ffmpeg frame size")?;
+                        let img = DynamicImage::ImageRgb8(img);
+                        let (data, pad_w, pad_h, ratio) =
+                            resize_with_pad(&img, imgsz as u32, config.pad_color, resizer)?;
+                        let (width, height) = (f.width as usize, f.height as usize);
+                        (
+                            data,
+                            width,
+                            height,
+                            (pad_w, pad_h),
+                            ratio,
+                            Some(img.into_rgb8()),
+                        )
+                    } else {
+                        let ndarray_frame = Array3::from_shape_vec((imgsz, imgsz, 3), f.data)
+                            .context("Unexpected ffmpeg frame size")?;
+                        let ndarray_frame = ndarray_frame.map(|&x| x as f32 / 255.0);
+                        let data = ndarray_frame.permuted_axes([2, 0, 1]);
+                        (data, orig_w, orig_h, padding, ratio, None)
+                    };
+                let timestamp = selection.timestamp(frame_num);
+                let frame = Frame {
+                    data,
+                    file: file.clone(),
+                    width,
+                    height,
+                    padding,
+                    ratio,
+                    frame_index: frame_num,
+                    total_frames: selected.len(),
+                    shoot_time: frame_time(shoot_time, timestamp),
+                    shoot_time_source,
+                    orientation: None,
+                    iframe: selection.iframe,
+                    timestamp,
+                    video_duration: info.duration,
+                    video_fps: info.fps,
+                    original,
+                };
+                if let Some(previous) = pending.replace(frame) {
+                    s.send(ArrayItem::Frame(previous))
+                        .map_err(|_| MediaError::QueueClosed)?;
+                }
+                sent += 1;
+                if next == selected.len() {
+                    // Dropping the iterator closes ffmpeg's stdout and stops decoding
+                    break;
+                }
             }
             _ => (),
         }
     }
 
+    if let Some(mut last) = pending {
+        // Fewer frames than ffprobe counted, the file is complete with the ones sent
+        last.total_frames = sent;
+        s.send(ArrayItem::Frame(last))
+            .map_err(|_| MediaError::QueueClosed)?;
+    }
+
     for e in ffmpeg_error {
         let error = MediaError::FfmpegError(e, file_path.clone());
         warn!("{:?}", error);
     }
 
-    if frames.is_empty() {
+    if decoded == 0 {
         let error = MediaError::VideoDecodeError(file_path).into();
         error!("{:?}", error);
         let frame_data = ArrayItem::ErrFile(ErrFile {
             file: file.clone(),
             error,
         });
-        s.send(frame_data).expect("Send video frame failed");
-    } else {
-        let sampled_frames = sample_evenly(&frames, max_frames.unwrap_or(frames.len()));
-
-        let shoot_time: Option<DateTime<Local>> = match get_video_date(&file.tmp_path.as_path()) {
-            Ok(shoot_time) => Some(shoot_time),
-            Err(_e) => None,
-        };
-
-        //calculate ratio and padding
-        let ratio = orig_w.max(orig_w) as f32 / imgsz as f32;
-        let pad = (orig_w as f32 - orig_h as f32).abs() / 2.0 / ratio;
-        let padding = if orig_w > orig_h {
-            (0, pad as usize)
-        } else {
-            (pad as usize, 0)
-        };
+        s.send(frame_data).map_err(|_| MediaError::QueueClosed)?;
+    } else if sent < selected.len() {
+        warn!(
+            "{}: ffprobe reported {} frames to sample but only {} decoded",
+            file_path,
+            selected.len(),
+            sent
+        );
+    }
 
-        let frames_length = sampled_frames.len();
+    Ok(())
+}
 
-        for f in sampled_frames.into_iter() {
-            let ndarray_frame = Array3::from_shape_vec((imgsz, imgsz, 3), f.data).unwrap();
-            let mut ndarray_frame = ndarray_frame.map(|&x| x as f32 / 255.0);
-            ndarray_frame = ndarray_frame.permuted_axes([2, 0, 1]);
-            let frame_data = ArrayItem::Frame(Frame {
-                data: ndarray_frame,
-                file: file.clone(),
-                width: orig_w,
-                height: orig_h,
-                padding,
-                ratio,
-                frame_index: f.frame_num as usize,
-                total_frames: frames_length,
-                shoot_time,
-                iframe,
-            });
-            s.send(frame_data).expect("Send video frame failed");
+/// Offset the video's start time by the frame's presentation time.
+fn frame_time(start: Option<DateTime<Local>>, timestamp: Option<f64>) -> Option<DateTime<Local>> {
+    match (start, timestamp) {
+        (Some(start), Some(timestamp)) => {
+            Some(start + chrono::Duration::milliseconds((timestamp * 1000.0).round() as i64))
         }
+        _ => start,
     }
-
-    Ok(())
 }
 
-fn get_image_date(parser: &mut MediaParser, image: &Path) -> Result<DateTime<Local>> {
+fn read_exif(parser: &mut MediaParser, image: &Path) -> Result<Exif> {
     let ms = MediaSource::file_path(image)?;
 
     let iter: ExifIter = parser.parse(ms)?;
-    let exif: Exif = iter.into();
+    Ok(iter.into())
+}
+
+fn get_image_date(exif: &Exif) -> Result<DateTime<Local>> {
     let shoot_time = exif
         .get(ExifTag::DateTimeOriginal)
         .or_else(|| exif.get(ExifTag::ModifyDate))
         .context("Neither DateTimeOriginal nor ModifyDate found")?;
-    let shoot_time = shoot_time.as_time().unwrap().with_timezone(&Local);
+    let shoot_time = shoot_time
+        .as_time()
+        .context("Shoot time is not a valid time")?
+        .with_timezone(&Local);
 
     Ok(shoot_time)
 }
 
-fn get_video_date(video: &Path) -> Result<DateTime<Local>> {
-    let metadata = metadata(video)?;
-    #[cfg(target_os = "windows")]
-    {
-        let m_time = metadata.modified()?;
-        let shoot_time: DateTime<Local> = m_time.clone().into();
+/// EXIF Orientation tag, `None` when missing or already upright.
+fn get_image_orientation(exif: &Exif) -> Option<u16> {
+    exif.get(ExifTag::Orientation)
+        .and_then(|value| value.as_u16())
+        .filter(|&orientation| orientation != 1)
+}
 
-        Ok(shoot_time)
+/// Recording start of a video. Embedded creation times are preferred, the
+/// file time is only a fallback since copying from SD cards resets it.
+fn get_video_date(
+    file: &FileItem,
+    info: &VideoInfo,
+    parser: &mut MediaParser,
+) -> Result<(DateTime<Local>, TimeSource)> {
+    let creation_time = info
+        .creation_date
+        .or_else(|| get_track_date(parser, file.tmp_path.as_path()))
+        .or(info.creation_time);
+    if let Some(creation_time) = creation_time {
+        return Ok((creation_time.with_timezone(&Local), TimeSource::Container));
     }
 
-    #[cfg(target_os = "linux")]
-    #[allow(deprecated)]
+    // The buffer copy has a fresh mtime, read the original file
+    let shoot_time = get_file_date(&file.file_path)?;
+    Ok((shoot_time, TimeSource::FileSystem))
+}
+
+/// Creation date from maker specific atoms that ffprobe doesn't expose.
+fn get_track_date(parser: &mut MediaParser, video: &Path) -> Option<DateTime<FixedOffset>> {
+    let ms = MediaSource::file_path(video).ok()?;
+    if !ms.has_track() {
+        return None;
+    }
+    let track: TrackInfo = parser.parse(ms).ok()?;
+    track
+        .get(TrackInfoTag::CreateDate)
+        .and_then(|value| value.as_time())
+        .filter(|time| time.timestamp() > 0)
+}
+
+fn get_file_date(path: &Path) -> Result<DateTime<Local>> {
+    let metadata = metadata(path)?;
+    #[cfg(target_os = "windows")]
     {
-        use chrono::NaiveDateTime;
-        use std::os::linux::fs::MetadataExt;
-        let m_time: i64 = metadata.st_mtime();
-        let c_time: i64 = metadata.st_ctime();
-        let shoot_time = m_time.min(c_time);
-        let offset = Local::now().offset().to_owned();
-        let shoot_time = NaiveDateTime::from_timestamp(shoot_time, 0);
-        let shoot_time = DateTime::<Local>::from_naive_utc_and_offset(shoot_time, offset);
+        let m_time = metadata.modified()?;
+        let shoot_time: DateTime<Local> = m_time.clone().into();
 
         Ok(shoot_time)
     }
 
-    #[cfg(target_os = "macos")]
-    #[allow(deprecated)]
+    #[cfg(unix)]
     {
-        use chrono::NaiveDateTime;
         use std::os::unix::fs::MetadataExt;
         let m_time: i64 = metadata.mtime();
         let c_time: i64 = metadata.ctime();
-        let shoot_time = m_time.min(c_time);
-        let offset = Local::now().offset().to_owned();
-        let shoot_time = NaiveDateTime::from_timestamp(shoot_time, 0);
-        let shoot_time = DateTime::<Local>::from_naive_utc_and_offset(shoot_time, offset);
+        // File times are seconds since the epoch in UTC
+        let shoot_time = DateTime::from_timestamp(m_time.min(c_time), 0)
+            .context("File time out of range")?
+            .with_timezone(&Local);
 
         Ok(shoot_time)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video_info() -> VideoInfo {
+        VideoInfo {
+            width: 1920,
+            height: 1080,
+            frame_count: 4,
+            keyframe_count: 2,
+            duration: Some(0.2),
+            fps: Some(20.0),
+            frame_times: vec![0.0, 0.05, 0.1, 0.15],
+            keyframe_times: vec![0.0, 0.1],
+            creation_date: None,
+            creation_time: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_frame_rate() {
+        assert_eq!(parse_frame_rate("25/1"), Some(25.0));
+        assert!((parse_frame_rate("30000/1001").unwrap() - 29.97).abs() < 0.001);
+        assert_eq!(parse_frame_rate("0/0"), None);
+        assert_eq!(parse_frame_rate("30"), None);
+    }
+
+    #[test]
+    fn test_frame_time() {
+        let start = DateTime::parse_from_rfc3339("2024-05-01T12:00:00+08:00")
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(
+            frame_time(Some(start), Some(1.5)),
+            Some(start + chrono::Duration::milliseconds(1500))
+        );
+        assert_eq!(frame_time(Some(start), None), Some(start));
+        assert_eq!(frame_time(None, Some(1.5)), None);
+    }
+
+    #[test]
+    fn test_parse_creation_time() {
+        let time = parse_creation_time("2024-05-01T04:00:00.000000Z").unwrap();
+        assert_eq!(time.to_rfc3339(), "2024-05-01T04:00:00+00:00");
+        // QuickTime creationdate keeps the local offset
+        let time = parse_creation_time("2024-05-01T12:00:00+0800").unwrap();
+        assert_eq!(time.to_rfc3339(), "2024-05-01T12:00:00+08:00");
+        // Container epochs of cameras without a clock
+        assert_eq!(parse_creation_time("1904-01-01T00:00:00.000000Z"), None);
+        assert_eq!(parse_creation_time("1970-01-01T00:00:00.000000Z"), None);
+        assert_eq!(parse_creation_time("not a time"), None);
+    }
+
+    #[test]
+    fn test_sampling_timestamps() {
+        assert_eq!(VideoSampling::Evenly.fps(), None);
+        assert_eq!(VideoSampling::Fps { fps: 2.0 }.fps(), Some(2.0));
+        assert_eq!(VideoSampling::Interval { seconds: 4.0 }.fps(), Some(0.25));
+
+        let info = video_info();
+        let mut selection = FrameSelection {
+            info: &info,
+            selected: vec![0, 1],
+            start_time: None,
+            iframe: false,
+            fps: None,
+        };
+        assert_eq!(selection.timestamp(3), Some(0.15));
+        assert_eq!(selection.timestamp(4), None);
+        selection.iframe = true;
+        assert_eq!(selection.timestamp(1), Some(0.1));
+        selection.fps = Some(0.5);
+        assert_eq!(selection.timestamp(3), Some(6.0));
+    }
+
+    #[test]
+    fn test_extension_kind() {
+        let extensions = MediaExtensions {
+            images: vec![".JPG".to_string()],
+            videos: vec!["mp4".to_string()],
+        };
+        let kind = |path: &str| extensions.kind(Path::new(path));
+        assert_eq!(kind("cam1/IMG_0001.jpg"), Some(MediaKind::Image));
+        assert_eq!(kind("cam1/VID_0001.MP4"), Some(MediaKind::Video));
+        assert_eq!(kind("cam1/VID_0001.mov"), None);
+        assert_eq!(kind("cam1/jpg"), None);
+    }
+}
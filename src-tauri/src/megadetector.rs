@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::DateTime;
+use serde::Serialize;
+
+use crate::export::{ExportError, ExportFrame, RunMetadata};
+
+/// Version of the MegaDetector batch output format written, 1.4 added videos.
+pub const FORMAT_VERSION: &str = "1.4";
+
+/// MegaDetector batch output, as read by Timelapse, EcoAssist and the
+/// MegaDetector postprocessing tools.
+#[derive(Debug, Serialize)]
+pub struct BatchOutput {
+    pub info: Info,
+    pub detection_categories: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub classification_categories: BTreeMap<String, String>,
+    pub images: Vec<Image>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Info {
+    pub format_version: String,
+    pub detector: String,
+    pub detection_completion_time: Option<String>,
+    pub detector_metadata: DetectorMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub classifier: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DetectorMetadata {
+    pub typical_detection_threshold: f32,
+    pub conservative_detection_threshold: f32,
+}
+
+/// One image or video. Videos hold the detections of all sampled frames.
+#[derive(Debug, Serialize)]
+pub struct Image {
+    /// Path relative to the media folder, with forward slashes
+    pub file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_detection_conf: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detections: Option<Vec<Detection>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Detection {
+    pub category: String,
+    pub conf: f32,
+    /// Normalized `[x_min, y_min, width, height]`
+    pub bbox: [f32; 4],
+    /// Frame number in the video at its native frame rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_number: Option<usize>,
+    /// `[category, score]` pairs of the species classifier, best first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub classifications: Vec<(String, f32)>,
+}
+
+fn round(value: f32, digits: i32) -> f32 {
+    let scale = 10f32.powi(digits);
+    (value * scale).round() / scale
+}
+
+/// Path of a media file relative to the media folder, as MegaDetector writes it.
+pub fn relative_path(file: &Path, folder_path: &Path) -> String {
+    file.strip_prefix(folder_path)
+        .unwrap_or(file)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+pub fn batch_output(
+    export_data: &[ExportFrame],
+    metadata: &RunMetadata,
+    folder_path: &Path,
+) -> BatchOutput {
+    // MegaDetector category ids start at 1
+    let detection_categories: BTreeMap<String, String> = metadata
+        .info
+        .model
+        .classes
+        .iter()
+        .enumerate()
+        .map(|(i, class)| ((i + 1).to_string(), class.to_lowercase()))
+        .collect();
+    let mut species: Vec<&str> = export_data
+        .iter()
+        .flat_map(|frame| frame.bboxes.iter().flatten())
+        .flat_map(|bbox| bbox.species.iter().flatten())
+        .map(|score| score.label.as_str())
+        .collect();
+    species.sort();
+    species.dedup();
+    let species_ids: BTreeMap<&str, String> = species
+        .iter()
+        .enumerate()
+        .map(|(i, label)| (*label, (i + 1).to_string()))
+        .collect();
+
+    // Frames of a video are grouped into one entry
+    let mut files: BTreeMap<String, Vec<&ExportFrame>> = BTreeMap::new();
+    for frame in export_data {
+        files
+            .entry(relative_path(&frame.file.file_path, folder_path))
+            .or_default()
+            .push(frame);
+    }
+
+    let mut images = Vec::with_capacity(files.len());
+    for (file, mut frames) in files {
+        frames.sort_by_key(|frame| frame.frame_index);
+        let is_video = frames.iter().any(|frame| frame.video_fps.is_some());
+        let mut detections = Vec::new();
+        let mut failures = Vec::new();
+        let mut detected = false;
+        for frame in &frames {
+            let Some(bboxes) = &frame.bboxes else {
+                failures.extend(frame.error.clone());
+                continue;
+            };
+            let (Some(width), Some(height)) = (frame.width, frame.height) else {
+                // Results written before frame sizes were recorded
+                if bboxes.is_empty() {
+                    detected = true;
+                } else {
+                    failures.push("Frame size unknown, boxes can't be normalized".to_string());
+                }
+                continue;
+            };
+            detected = true;
+            let frame_number = is_video.then(|| match (frame.timestamp, frame.video_fps) {
+                (Some(timestamp), Some(fps)) => (timestamp * fps).round() as usize,
+                _ => frame.frame_index,
+            });
+            for bbox in bboxes {
+                let (width, height) = (width as f32, height as f32);
+                detections.push(Detection {
+                    category: (bbox.class + 1).to_string(),
+                    conf: round(bbox.score, 3),
+                    bbox: [
+                        round(bbox.x1 / width, 4),
+                        round(bbox.y1 / height, 4),
+                        round((bbox.x2 - bbox.x1) / width, 4),
+                        round((bbox.y2 - bbox.y1) / height, 4),
+                    ],
+                    frame_number,
+                    classifications: bbox
+                        .species
+                        .iter()
+                        .flatten()
+                        .map(|score| {
+                            (
+                                species_ids[score.label.as_str()].clone(),
+                                round(score.score, 3),
+                            )
+                        })
+                        .collect(),
+                });
+            }
+        }
+
+        // A video only fails when none of its frames were detected
+        images.push(Image {
+            file,
+            frame_rate: frames.iter().find_map(|frame| frame.video_fps),
+            max_detection_conf: detected.then(|| {
+                detections
+                    .iter()
+                    .map(|detection| detection.conf)
+                    .fold(0.0, f32::max)
+            }),
+            detections: detected.then_some(detections),
+            failure: (!detected).then(|| failures.join("; ")),
+        });
+    }
+
+    let config = &metadata.config;
+    BatchOutput {
+        info: Info {
+            format_version: FORMAT_VERSION.to_string(),
+            detector: metadata.info.model.name.clone(),
+            detection_completion_time: metadata.info.end_time.as_ref().map(|end_time| {
+                DateTime::parse_from_rfc3339(end_time)
+                    .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|_| end_time.clone())
+            }),
+            detector_metadata: DetectorMetadata {
+                typical_detection_threshold: config.confidence_threshold,
+                conservative_detection_threshold: config
+                    .class_thresholds
+                    .values()
+                    .copied()
+                    .fold(config.confidence_threshold, f32::min),
+            },
+            classifier: metadata.info.classifier.clone(),
+        },
+        detection_categories,
+        classification_categories: species_ids
+            .into_iter()
+            .map(|(label, id)| (id, label.to_string()))
+            .collect(),
+        images,
+    }
+}
+
+pub fn write_batch_output(
+    export_data: &[ExportFrame],
+    metadata: &RunMetadata,
+    folder_path: &Path,
+) -> Result<(), ExportError> {
+    let output = batch_output(export_data, metadata, folder_path);
+    let json = serde_json::to_string_pretty(&output)?;
+    let mut file = File::create(folder_path.join("result.megadetector.json"))?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::export::tests::{test_frame, test_metadata};
+
+    #[test]
+    fn test_batch_output() {
+        let mut failed = test_frame();
+        failed.file.file_path = PathBuf::from("/data/c.jpg");
+        failed.bboxes = None;
+        failed.error = Some("Failed to decode".to_string());
+        let mut second = test_frame();
+        second.frame_index = 4;
+        second.timestamp = Some(4.0);
+        let mut first = test_frame();
+        first.file.file_path = PathBuf::from("/data/a/b.mp4");
+        second.file.file_path = first.file.file_path.clone();
+
+        let output = batch_output(
+            &[failed, second, first],
+            &test_metadata(),
+            Path::new("/data"),
+        );
+        assert_eq!(output.detection_categories["1"], "animal");
+        assert_eq!(output.images.len(), 2);
+
+        let video = &output.images[0];
+        assert_eq!(video.file, "a/b.mp4");
+        assert_eq!(video.frame_rate, Some(29.97));
+        let detections = video.detections.as_ref().unwrap();
+        assert_eq!(detections.len(), 2);
+        assert_eq!(detections[0].frame_number, Some(90));
+        assert_eq!(detections[0].category, "1");
+        assert!((detections[0].bbox[2] - 2.0 / 1920.0).abs() < 1e-4);
+
+        assert!(output.images[1].detections.is_none());
+        assert_eq!(
+            output.images[1].failure.as_deref(),
+            Some("Failed to decode")
+        );
+    }
+}
@@ -0,0 +1,626 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::export::{parse_shoot_time, read_result, ExportFrame};
+use crate::megadetector::relative_path;
+use crate::sequence::{propagate_strongest, sequences, SequenceOptions};
+use crate::template::{PathTemplate, TemplateContext};
+use crate::utils::SpeciesScore;
+
+/// Journal of the last organize in the media folder, undo puts its files back.
+pub const JOURNAL_FILE: &str = "organize.journal.jsonl";
+
+/// Class folder of files without detections.
+pub const BLANK: &str = "Blank";
+
+/// Classes of MegaDetector, for results written before model classes were recorded.
+pub(crate) const DEFAULT_CLASSES: [&str; 3] = ["Animal", "Person", "Vehicle"];
+
+/// Conflicts listed in the summary of a plan, the JSON plan has all of them.
+const SUMMARY_CONFLICTS: usize = 20;
+
+/// How files get to their class folder.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum OrganizeMode {
+    #[default]
+    Move,
+    /// Originals stay where they are
+    Copy,
+    /// Takes no space, the destination must be on the same volume
+    Hardlink,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OrganizeOptions {
+    /// Every file of a sequence goes to the folder of its strongest class
+    pub guess: bool,
+    /// Regroup sequences in guess mode instead of using the ones recorded
+    /// in the result
+    pub sequence: Option<SequenceOptions>,
+    pub mode: OrganizeMode,
+    /// Destination relative to the media folder, a class folder next to
+    /// each file when `None`. See [`PathTemplate`]
+    pub template: Option<String>,
+}
+
+/// One file move, as written to the journal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Move {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    #[serde(default)]
+    pub class: String,
+    #[serde(default)]
+    pub mode: OrganizeMode,
+}
+
+/// Why a planned move would be left out or refused.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum Conflict {
+    /// The last organize was not undone, organizing is refused
+    PendingUndo { journal: PathBuf },
+    /// The file is not where the result says, it was moved or deleted since
+    Missing { from: PathBuf },
+    /// A file with the same name is in the destination already
+    Exists { from: PathBuf, to: PathBuf },
+    /// Several files would go to the same destination, only the first moves
+    SameDestination { to: PathBuf, from: Vec<PathBuf> },
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::PendingUndo { journal } => {
+                write!(
+                    f,
+                    "{} exists, undo the last organize first",
+                    journal.display()
+                )
+            }
+            Conflict::Missing { from } => write!(f, "{} not found", from.display()),
+            Conflict::Exists { from, to } => write!(
+                f,
+                "{} exists, {} stays in place",
+                to.display(),
+                from.display()
+            ),
+            Conflict::SameDestination { to, from } => {
+                write!(f, "{} files would be moved to {}", from.len(), to.display())
+            }
+        }
+    }
+}
+
+/// What organizing a result would do, worked out without touching any file.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrganizePlan {
+    /// Media folder of the result
+    pub folder: PathBuf,
+    pub moves: Vec<Move>,
+    /// Files per class of each folder, relative to the media folder
+    pub counts: BTreeMap<String, BTreeMap<String, usize>>,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl fmt::Display for OrganizePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} files to move in {}",
+            self.moves.len(),
+            self.folder.display()
+        )?;
+        for (folder, classes) in &self.counts {
+            let classes: Vec<String> = classes
+                .iter()
+                .map(|(class, count)| format!("{} {}", class, count))
+                .collect();
+            let folder = if folder.is_empty() { "." } else { folder };
+            writeln!(f, "  {}: {}", folder, classes.join(", "))?;
+        }
+        if !self.conflicts.is_empty() {
+            writeln!(f, "{} conflicts:", self.conflicts.len())?;
+            for conflict in self.conflicts.iter().take(SUMMARY_CONFLICTS) {
+                writeln!(f, "  {}", conflict)?;
+            }
+            if self.conflicts.len() > SUMMARY_CONFLICTS {
+                writeln!(f, "  and {} more", self.conflicts.len() - SUMMARY_CONFLICTS)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrganizeSummary {
+    /// Files moved, copied or linked, or put back by undo
+    pub moved: usize,
+    /// Files left where they are because they are missing or the destination is taken
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Class folder of each file with a detected frame. Earlier classes of the
+/// model are stronger, a file with an animal and a person goes to the animal
+/// folder. Files without boxes are blank.
+pub fn file_classes(
+    export_data: &[ExportFrame],
+    classes: &[String],
+    folder_path: &Path,
+    options: &OrganizeOptions,
+) -> BTreeMap<PathBuf, String> {
+    let blank = classes.len();
+    let mut ranks: HashMap<&Path, usize> = HashMap::new();
+    for frame in export_data {
+        let Some(bboxes) = &frame.bboxes else {
+            continue;
+        };
+        let rank = bboxes
+            .iter()
+            .map(|bbox| bbox.class)
+            .filter(|&class| class < blank)
+            .min()
+            .unwrap_or(blank);
+        let file_rank = ranks.entry(&frame.file.file_path).or_insert(blank);
+        *file_rank = (*file_rank).min(rank);
+    }
+
+    if options.guess {
+        let frames: Vec<&ExportFrame> = export_data.iter().collect();
+        let seqs = sequences(&frames, folder_path, options.sequence.as_ref());
+        let frame_ranks: Vec<Option<usize>> = frames
+            .iter()
+            .map(|frame| ranks.get(frame.file.file_path.as_path()).copied())
+            .collect();
+        for (frame, rank) in frames.iter().zip(propagate_strongest(&seqs, &frame_ranks)) {
+            if let Some(rank) = rank {
+                ranks.insert(&frame.file.file_path, rank);
+            }
+        }
+    }
+
+    ranks
+        .into_iter()
+        .map(|(file, rank)| {
+            let class = classes.get(rank).map(String::as_str).unwrap_or(BLANK);
+            (file.to_path_buf(), class.to_string())
+        })
+        .collect()
+}
+
+/// Destinations of the files of a result by the template of the options,
+/// `site/IMG_1.JPG` to `site/Animal/IMG_1.JPG` without one.
+pub fn moves(
+    export_data: &[ExportFrame],
+    classes: &[String],
+    folder_path: &Path,
+    options: &OrganizeOptions,
+) -> Result<Vec<Move>> {
+    let template = match &options.template {
+        Some(template) => template.parse::<PathTemplate>()?,
+        None => PathTemplate::default(),
+    };
+    // First frame and best species of each file
+    let mut first_frames: HashMap<&Path, &ExportFrame> = HashMap::new();
+    let mut species: HashMap<&Path, &SpeciesScore> = HashMap::new();
+    for frame in export_data {
+        let file = frame.file.file_path.as_path();
+        first_frames.entry(file).or_insert(frame);
+        let scores = frame
+            .bboxes
+            .iter()
+            .flatten()
+            .filter_map(|bbox| bbox.species.as_ref()?.first());
+        for score in scores {
+            let best = species.entry(file).or_insert(score);
+            if score.score > best.score {
+                *best = score;
+            }
+        }
+    }
+
+    let mut moves = Vec::new();
+    for (from, class) in file_classes(export_data, classes, folder_path, options) {
+        let Some(folder) = from
+            .parent()
+            .and_then(|parent| parent.strip_prefix(folder_path).ok())
+        else {
+            log::warn!("{} is not in the media folder, skipped", from.display());
+            continue;
+        };
+        let frame = first_frames[from.as_path()];
+        let to = folder_path.join(
+            template.render(&TemplateContext {
+                class: &class,
+                species: species
+                    .get(from.as_path())
+                    .map(|score| score.label.as_str()),
+                folder,
+                file: &from,
+                shoot_time: frame.shoot_time.as_deref().and_then(parse_shoot_time),
+            })?,
+        );
+        if to != from {
+            moves.push(Move {
+                from,
+                to,
+                class,
+                mode: options.mode,
+            });
+        }
+    }
+    Ok(moves)
+}
+
+/// Whether something, a dangling symlink too, is at the path.
+fn occupied(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
+}
+
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to)
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(original, link)
+}
+
+/// Put a file at its destination.
+fn place(m: &Move) -> std::io::Result<()> {
+    if let Some(parent) = m.to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match m.mode {
+        OrganizeMode::Move => std::fs::rename(&m.from, &m.to),
+        OrganizeMode::Copy => std::fs::copy(&m.from, &m.to).map(|_| ()),
+        OrganizeMode::Hardlink => std::fs::hard_link(&m.from, &m.to),
+        OrganizeMode::Symlink => symlink(&m.from, &m.to),
+    }
+}
+
+/// Take a placed file back, `false` if there is nothing to take back.
+fn revert(m: &Move) -> std::io::Result<bool> {
+    match m.mode {
+        // Never moved, or moved again since
+        OrganizeMode::Move if occupied(&m.from) || !occupied(&m.to) => return Ok(false),
+        OrganizeMode::Move => move_file(&m.to, &m.from)?,
+        // A copy is only removed while the original is still there
+        OrganizeMode::Copy | OrganizeMode::Hardlink if !m.from.is_file() || !m.to.is_file() => {
+            return Ok(false)
+        }
+        OrganizeMode::Symlink
+            if !m
+                .to
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_symlink()) =>
+        {
+            return Ok(false)
+        }
+        OrganizeMode::Copy | OrganizeMode::Hardlink | OrganizeMode::Symlink => {
+            std::fs::remove_file(&m.to)?
+        }
+    }
+    Ok(true)
+}
+
+fn journal_move(journal: &mut BufWriter<File>, m: &Move) -> Result<()> {
+    serde_json::to_writer(&mut *journal, m)?;
+    journal.write_all(b"\n")?;
+    journal.flush()?;
+    Ok(())
+}
+
+fn read_journal(path: &Path) -> Result<Vec<Move>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut moves = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            moves.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(moves)
+}
+
+/// Files placed by the last organize of a media folder, relative to it.
+/// Indexing skips them so copies and links are not detected again.
+pub fn organized_files<P: AsRef<Path>>(folder_path: P) -> Result<HashSet<PathBuf>> {
+    let folder_path = std::fs::canonicalize(folder_path)?;
+    let journal_path = folder_path.join(JOURNAL_FILE);
+    if !journal_path.is_file() {
+        return Ok(HashSet::new());
+    }
+    Ok(read_journal(&journal_path)?
+        .into_iter()
+        .filter_map(|m| Some(m.to.strip_prefix(&folder_path).ok()?.to_path_buf()))
+        .collect())
+}
+
+/// Plan organizing a result: the moves, how many files go to each class
+/// folder and what is in the way. Only reads the result and checks paths.
+pub fn dry_run<P: AsRef<Path>>(result_path: P, options: &OrganizeOptions) -> Result<OrganizePlan> {
+    let result_path = std::fs::canonicalize(result_path)?;
+    let folder_path = result_path
+        .parent()
+        .ok_or_else(|| anyhow!("Result file has no parent folder"))?
+        .to_path_buf();
+
+    let (export_data, metadata) = read_result(&result_path)?;
+    let classes = match metadata {
+        Some(metadata) => metadata.info.model.classes,
+        None => {
+            log::warn!("Result has no model classes, assuming MegaDetector classes");
+            DEFAULT_CLASSES.map(String::from).to_vec()
+        }
+    };
+    let moves = moves(&export_data, &classes, &folder_path, options)?;
+
+    let mut counts: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+    let mut conflicts = Vec::new();
+    let journal = folder_path.join(JOURNAL_FILE);
+    if journal.exists() {
+        conflicts.push(Conflict::PendingUndo { journal });
+    }
+    let mut destinations: BTreeMap<&Path, Vec<PathBuf>> = BTreeMap::new();
+    for m in &moves {
+        let folder = m.from.parent().unwrap_or(&folder_path);
+        *counts
+            .entry(relative_path(folder, &folder_path))
+            .or_default()
+            .entry(m.class.clone())
+            .or_default() += 1;
+        if !m.from.exists() {
+            conflicts.push(Conflict::Missing {
+                from: m.from.clone(),
+            });
+        } else if occupied(&m.to) {
+            conflicts.push(Conflict::Exists {
+                from: m.from.clone(),
+                to: m.to.clone(),
+            });
+        }
+        destinations.entry(&m.to).or_default().push(m.from.clone());
+    }
+    conflicts.extend(
+        destinations
+            .into_iter()
+            .filter(|(_, from)| from.len() > 1)
+            .map(|(to, from)| Conflict::SameDestination {
+                to: to.to_path_buf(),
+                from,
+            }),
+    );
+
+    Ok(OrganizePlan {
+        folder: folder_path,
+        moves,
+        counts,
+        conflicts,
+    })
+}
+
+/// Move, copy or link the files of a result into class folders. Every file is
+/// journaled, a move before it happens so an interrupted organize can be undone.
+pub fn organize<P: AsRef<Path>>(
+    result_path: P,
+    options: &OrganizeOptions,
+    on_progress: &dyn Fn(usize, usize),
+) -> Result<OrganizeSummary> {
+    let plan = dry_run(result_path, options)?;
+    if let Some(conflict @ Conflict::PendingUndo { .. }) = plan.conflicts.first() {
+        return Err(anyhow!("{}", conflict));
+    }
+    let moves = plan.moves;
+    let journal_path = plan.folder.join(JOURNAL_FILE);
+    let mut journal = BufWriter::new(File::create(&journal_path)?);
+
+    let mut summary = OrganizeSummary::default();
+    for (i, m) in moves.iter().enumerate() {
+        if !m.from.exists() {
+            log::warn!("{} not found, skipped", m.from.display());
+            summary.skipped += 1;
+        } else if occupied(&m.to) {
+            log::warn!(
+                "{} exists, {} is left in place",
+                m.to.display(),
+                m.from.display()
+            );
+            summary.skipped += 1;
+        } else {
+            // Copies and links are journaled once made, undo only removes what organize made
+            if m.mode == OrganizeMode::Move {
+                journal_move(&mut journal, m)?;
+            }
+            match place(m) {
+                Ok(()) => {
+                    if m.mode != OrganizeMode::Move {
+                        journal_move(&mut journal, m)?;
+                    }
+                    summary.moved += 1;
+                }
+                Err(e) => {
+                    log::error!("Failed to organize {}: {}", m.from.display(), e);
+                    summary.failed += 1;
+                }
+            }
+        }
+        on_progress(i + 1, moves.len());
+    }
+    journal.get_ref().sync_data()?;
+    drop(journal);
+    // Nothing to undo, don't block organizing again
+    if summary.moved == 0 {
+        std::fs::remove_file(&journal_path)?;
+    }
+    log::info!(
+        "Organized {}: {} moved, {} skipped, {} failed",
+        plan.folder.display(),
+        summary.moved,
+        summary.skipped,
+        summary.failed
+    );
+    Ok(summary)
+}
+
+/// Put the files of the last organize of a media folder back, removing copies
+/// and links. The journal is kept if some files failed, so undo can be retried.
+pub fn undo<P: AsRef<Path>>(
+    folder_path: P,
+    on_progress: &dyn Fn(usize, usize),
+) -> Result<OrganizeSummary> {
+    let folder_path = std::fs::canonicalize(folder_path)?;
+    let journal_path = folder_path.join(JOURNAL_FILE);
+    if !journal_path.is_file() {
+        return Err(anyhow!(
+            "Nothing to undo, {} not found",
+            journal_path.display()
+        ));
+    }
+    let moves = read_journal(&journal_path)?;
+
+    let mut summary = OrganizeSummary::default();
+    let mut class_dirs = BTreeSet::new();
+    for (i, m) in moves.iter().rev().enumerate() {
+        match revert(m) {
+            Ok(true) => summary.moved += 1,
+            Ok(false) => summary.skipped += 1,
+            Err(e) => {
+                log::error!("Failed to undo {}: {}", m.to.display(), e);
+                summary.failed += 1;
+            }
+        }
+        class_dirs.extend(m.to.parent().map(Path::to_path_buf));
+        on_progress(i + 1, moves.len());
+    }
+    // Folders made by organize are removed once empty, deepest first
+    for dir in class_dirs.iter().rev() {
+        for dir in dir
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&folder_path) && *dir != folder_path)
+        {
+            if std::fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    if summary.failed == 0 {
+        std::fs::remove_file(&journal_path)?;
+    } else {
+        log::warn!(
+            "{} files could not be moved back, keeping {}",
+            summary.failed,
+            journal_path.display()
+        );
+    }
+    log::info!(
+        "Undid organize: {} moved back, {} skipped, {} failed",
+        summary.moved,
+        summary.skipped,
+        summary.failed
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::export::tests::{still, test_metadata, TempDir};
+    use crate::store::{ResultStore, STORE_FILE};
+
+    #[test]
+    fn test_organize_and_undo() {
+        let tmp = TempDir::new("organize");
+        let dir = std::fs::canonicalize(tmp.path()).unwrap();
+        let site = dir.join("site");
+        std::fs::create_dir_all(&site).unwrap();
+        let frames = vec![
+            still(
+                site.join("a.jpg"),
+                Some("2024-05-01 12:00:00 +08:00"),
+                Some(1),
+            ),
+            still(
+                site.join("b.jpg"),
+                Some("2024-05-01 12:00:10 +08:00"),
+                Some(0),
+            ),
+            still(site.join("c.jpg"), Some("2024-05-01 12:00:20 +08:00"), None),
+            still(site.join("d.jpg"), Some("2024-05-01 14:00:00 +08:00"), None),
+        ];
+        let store = ResultStore::create(dir.join(STORE_FILE), &test_metadata()).unwrap();
+        for frame in &frames {
+            std::fs::write(&frame.file.file_path, b"").unwrap();
+            store.insert(frame).unwrap();
+        }
+        store.commit().unwrap();
+
+        let classes = test_metadata().info.model.classes;
+        let plain = file_classes(&frames, &classes, &dir, &OrganizeOptions::default());
+        assert_eq!(plain[&site.join("a.jpg")], "Person");
+        assert_eq!(plain[&site.join("c.jpg")], BLANK);
+
+        let options = OrganizeOptions {
+            guess: true,
+            ..Default::default()
+        };
+        let plan = dry_run(dir.join(STORE_FILE), &options).unwrap();
+        assert_eq!(plan.counts["site"]["Animal"], 3);
+        assert_eq!(plan.counts["site"][BLANK], 1);
+        assert!(plan.conflicts.is_empty());
+        assert!(site.join("a.jpg").is_file());
+
+        let summary = organize(dir.join(STORE_FILE), &options, &|_, _| {}).unwrap();
+        assert_eq!(summary.moved, 4);
+        // a and c are in the sequence of b, d an hour later is blank
+        assert!(site.join("Animal/a.jpg").is_file());
+        assert!(site.join("Animal/c.jpg").is_file());
+        assert!(site.join("Blank/d.jpg").is_file());
+        assert!(organize(dir.join(STORE_FILE), &options, &|_, _| {}).is_err());
+        let plan = dry_run(dir.join(STORE_FILE), &options).unwrap();
+        assert!(matches!(plan.conflicts[0], Conflict::PendingUndo { .. }));
+        assert_eq!(plan.conflicts.len(), 5);
+
+        let summary = undo(&dir, &|_, _| {}).unwrap();
+        assert_eq!(summary.moved, 4);
+        assert!(site.join("a.jpg").is_file());
+        assert!(!site.join("Animal").exists());
+        assert!(!dir.join(JOURNAL_FILE).exists());
+
+        let options = OrganizeOptions {
+            mode: OrganizeMode::Copy,
+            template: Some("{class}/{date:%Y-%m}/{filename}".to_string()),
+            ..Default::default()
+        };
+        let summary = organize(dir.join(STORE_FILE), &options, &|_, _| {}).unwrap();
+        assert_eq!(summary.moved, 4);
+        assert!(dir.join("Person/2024-05/a.jpg").is_file());
+        assert!(organized_files(&dir)
+            .unwrap()
+            .contains(Path::new("Person/2024-05/a.jpg")));
+        assert!(site.join("a.jpg").is_file());
+        undo(&dir, &|_, _| {}).unwrap();
+        assert!(!dir.join("Person").exists());
+        assert!(site.join("a.jpg").is_file());
+    }
+}
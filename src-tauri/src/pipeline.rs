@@ -0,0 +1,610 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use crossbeam_channel::{bounded, unbounded};
+use log::error;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::classifier::load_classifier_config;
+use crate::detect::{class_thresholds, detect_worker, DetectConfig};
+use crate::export::{
+    export, export_worker, read_result, ExportFrame, ModelInfo, RunConfig, RunInfo, RunMetadata,
+    RESULT_FORMAT_VERSION,
+};
+use crate::media::{media_worker, MediaConfig};
+use crate::store::{completed_files, read_store, ResultStore, StoreReader, STORE_FILE};
+use crate::tiling::Tiling;
+use crate::utils::{file_md5, index_files_and_folders, load_model_config, FileItem};
+use crate::{io, Config, ConfigOptions, EpConfig, ExportFormat, JobControl};
+
+/// Callbacks for a running [`Pipeline`]. All methods have empty defaults so
+/// implementors only override what they need.
+pub trait PipelineObserver: Send + Sync {
+    /// Called once files are indexed, with the number of files left to process.
+    fn on_start(&self, _total_files: usize) {}
+
+    /// Called each time a media file has been decoded and queued for detection.
+    fn on_progress(&self, _processed: usize, _total: usize) {}
+
+    /// Called for every file that failed to decode or detect. The job goes on.
+    fn on_file_error(&self, _frame: &ExportFrame) {}
+
+    /// Called once results are written.
+    fn on_complete(&self, _summary: &RunSummary) {}
+}
+
+/// Observer that ignores every event.
+pub struct NoopObserver;
+
+impl PipelineObserver for NoopObserver {}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunSummary {
+    pub total_files: usize,
+    pub processed_files: usize,
+    pub frames: usize,
+    pub cancelled: bool,
+    pub elapsed: Duration,
+    pub result_path: PathBuf,
+    /// Store of the frames, the file to resume a cancelled job from
+    pub store_path: PathBuf,
+}
+
+/// A configured detection job, independent of the GUI.
+///
+/// ```no_run
+/// use megascops_local_lib::Pipeline;
+///
+/// let summary = Pipeline::builder()
+///     .folder("/data/deployment")
+///     .model("models/toml/MDV6-yolov9e_d_pp.toml")
+///     .build()?
+///     .run()?;
+/// println!("{} frames in {:?}", summary.frames, summary.elapsed);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Pipeline {
+    config: Config,
+    observer: Arc<dyn PipelineObserver>,
+    control: Arc<JobControl>,
+}
+
+#[derive(Default)]
+pub struct PipelineBuilder {
+    config: Config,
+    observer: Option<Arc<dyn PipelineObserver>>,
+    control: Option<Arc<JobControl>>,
+}
+
+impl PipelineBuilder {
+    /// Replace the whole config, e.g. one deserialized from the GUI store or a TOML file.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn config_options(mut self, config_options: ConfigOptions) -> Self {
+        self.config.config_options = config_options;
+        self
+    }
+
+    pub fn folder<P: AsRef<Path>>(mut self, folder: P) -> Self {
+        self.config.detect_options.selected_folder = folder.as_ref().to_string_lossy().into_owned();
+        self
+    }
+
+    /// Path of the model config toml.
+    pub fn model<P: AsRef<Path>>(mut self, model: P) -> Self {
+        self.config.detect_options.model = model.as_ref().to_string_lossy().into_owned();
+        self
+    }
+
+    pub fn resume_path<P: AsRef<Path>>(mut self, resume_path: P) -> Self {
+        self.config.detect_options.resume_path =
+            Some(resume_path.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    pub fn ep(mut self, ep: Vec<EpConfig>) -> Self {
+        self.config.detect_options.ep = ep;
+        self
+    }
+
+    pub fn confidence_threshold(mut self, confidence_threshold: f32) -> Self {
+        self.config.config_options.confidence_threshold = confidence_threshold;
+        self
+    }
+
+    pub fn iou_threshold(mut self, iou_threshold: f32) -> Self {
+        self.config.config_options.iou_threshold = iou_threshold;
+        self
+    }
+
+    pub fn export_format(mut self, export_format: ExportFormat) -> Self {
+        self.config.config_options.export_format = export_format;
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.config.config_options.batch_size = batch_size;
+        self
+    }
+
+    pub fn tiling(mut self, tiling: Tiling) -> Self {
+        self.config.config_options.tiling = Some(tiling);
+        self
+    }
+
+    /// Species classifier toml run on Animal crops.
+    pub fn classifier(mut self, classifier: impl Into<String>) -> Self {
+        self.config.config_options.classifier = Some(classifier.into());
+        self
+    }
+
+    pub fn observer(mut self, observer: Arc<dyn PipelineObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Share a [`JobControl`] to cancel or pause the job from another thread.
+    pub fn control(mut self, control: Arc<JobControl>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    pub fn build(self) -> Result<Pipeline> {
+        let config = self.config;
+        if config.detect_options.selected_folder.is_empty() {
+            return Err(anyhow!("No media folder selected"));
+        }
+        if config.detect_options.model.is_empty() {
+            return Err(anyhow!("No model selected"));
+        }
+        if config
+            .detect_options
+            .ep
+            .iter()
+            .map(|ep| ep.workers)
+            .sum::<usize>()
+            == 0
+        {
+            return Err(anyhow!("At least one detect worker is required"));
+        }
+        if config.config_options.check_point == 0 {
+            return Err(anyhow!("Checkpoint should be greater than 0"));
+        }
+        if let Some(fps) = config.config_options.video_sampling.fps() {
+            if !fps.is_finite() || fps <= 0.0 {
+                return Err(anyhow!("Video sampling rate should be greater than 0"));
+            }
+        }
+        if config.config_options.max_frames == Some(0) {
+            return Err(anyhow!("Max frames should be greater than 0"));
+        }
+        if config.config_options.batch_size == 0 {
+            return Err(anyhow!("Batch size should be greater than 0"));
+        }
+        if let Some(tiling) = &config.config_options.tiling {
+            if !(0.0..1.0).contains(&tiling.overlap) {
+                return Err(anyhow!("Tile overlap should be in [0, 1)"));
+            }
+        }
+        Ok(Pipeline {
+            config,
+            observer: self.observer.unwrap_or_else(|| Arc::new(NoopObserver)),
+            control: self.control.unwrap_or_default(),
+        })
+    }
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn control(&self) -> Arc<JobControl> {
+        Arc::clone(&self.control)
+    }
+
+    /// Run the job to completion (or cancellation) on the calling thread.
+    pub fn run(&self) -> Result<RunSummary> {
+        let config = &self.config;
+        let control = &self.control;
+
+        cleanup_buffer(&config.config_options.buffer_path)?;
+
+        let folder_path = PathBuf::from(&config.detect_options.selected_folder);
+        let folder_path = std::fs::canonicalize(folder_path)?;
+
+        let model_config = load_model_config(&config.detect_options.model)?;
+        let class_thresholds = class_thresholds(
+            &model_config.class_map(),
+            config.config_options.confidence_threshold,
+            &config.config_options.class_thresholds,
+            &config.config_options.include_classes,
+            &config.config_options.exclude_classes,
+        )
+        .map_err(|e| anyhow!(e))?;
+        let class_map = model_config.class_map();
+        let effective: BTreeMap<String, f32> = class_thresholds
+            .iter()
+            .map(|(id, threshold)| (class_map[id].clone(), *threshold))
+            .collect();
+        log::info!(
+            "Confidence thresholds: {}",
+            effective
+                .iter()
+                .map(|(class, threshold)| format!("{}={}", class, threshold))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let classifier_config = match &config.config_options.classifier {
+            Some(classifier) => Some(load_classifier_config(classifier)?),
+            None => None,
+        };
+
+        let media_config = MediaConfig {
+            imgsz: model_config.imgsz,
+            pad_color: model_config.preprocess.pad_color,
+            keep_original: classifier_config.is_some() || config.config_options.tiling.is_some(),
+            full_resolution_video: config.config_options.tiling.is_some(),
+            iframe: config.config_options.iframe_only,
+            max_frames: config.config_options.max_frames,
+            sampling: config.config_options.video_sampling,
+            extensions: config.config_options.extensions.clone(),
+        };
+        let metadata = RunMetadata {
+            info: RunInfo {
+                format_version: RESULT_FORMAT_VERSION,
+                app_version: env!("CARGO_PKG_VERSION").to_string(),
+                model: ModelInfo {
+                    name: model_config.name.clone(),
+                    path: model_config.path.clone(),
+                    classes: model_config.classes.iter().cloned().collect(),
+                    md5: file_md5(&model_config.path).ok(),
+                    architecture: model_config.architecture(),
+                },
+                classifier: classifier_config.as_ref().map(|c| c.name.clone()),
+                ep: config.detect_options.ep.clone(),
+                start_time: Local::now().to_rfc3339(),
+                end_time: None,
+            },
+            config: RunConfig {
+                confidence_threshold: config.config_options.confidence_threshold,
+                iou_threshold: config.config_options.iou_threshold,
+                class_thresholds: effective,
+                iframe_only: config.config_options.iframe_only,
+                max_frames: config.config_options.max_frames,
+                video_sampling: config.config_options.video_sampling,
+                extensions: config.config_options.extensions.clone(),
+                tiling: config.config_options.tiling,
+            },
+        };
+        let start = Instant::now();
+
+        let mut file_paths = index_files_and_folders(&folder_path, &media_config.extensions)?;
+        // Match stored frames to files by path, ids change when files are added
+        let indexed: HashMap<PathBuf, FileItem> = file_paths
+            .iter()
+            .map(|file| (file.file_path.clone(), file.clone()))
+            .collect();
+
+        let store_path = folder_path.join(STORE_FILE);
+        let store = match &config.detect_options.resume_path {
+            Some(checkpoint_path) => {
+                resume_from_checkpoint(checkpoint_path, &store_path, &mut file_paths, &metadata)?
+            }
+            None => ResultStore::create(&store_path, &metadata)?,
+        };
+        let store = Arc::new(store);
+
+        let total_files = file_paths.len();
+        self.observer.on_start(total_files);
+
+        let mut detect_handles = vec![];
+
+        let mut export_handles = vec![];
+
+        let worker_sum: usize = config.detect_options.ep.iter().map(|ep| ep.workers).sum();
+
+        let (array_q_s, array_q_r) = bounded(config.config_options.batch_size * worker_sum * 2);
+
+        let (export_q_s, export_q_r) = unbounded();
+
+        for d in config.detect_options.ep.iter() {
+            let detect_config = Arc::new(DetectConfig {
+                device: d.id.clone(),
+                ep: d.ep.clone(),
+                model_path: model_config.path.clone(),
+                target_size: model_config.imgsz,
+                class_map: class_map.clone(),
+                iou_thres: config.config_options.iou_threshold,
+                conf_thres: config.config_options.confidence_threshold,
+                class_thresholds: class_thresholds.clone(),
+                batch_size: config.config_options.batch_size,
+                timeout: 50,
+                model_name: model_config.name.clone(),
+                architecture: model_config.architecture(),
+                tensors: model_config.tensors.clone(),
+                preprocess: model_config.preprocess.clone(),
+                classifier: classifier_config.clone(),
+                tiling: config.config_options.tiling,
+            });
+            for _ in 0..d.workers {
+                let detect_config = Arc::clone(&detect_config);
+                let array_q_r = array_q_r.clone();
+                let export_q_s = export_q_s.clone();
+                let detect_handle =
+                    detect_worker(detect_config, array_q_r, export_q_s, Arc::clone(control));
+                detect_handles.push(detect_handle);
+            }
+        }
+        // Only workers hold receivers, so media workers stop instead of blocking if they all fail
+        drop(array_q_r);
+
+        for _ in 0..4 {
+            let export_q_r = export_q_r.clone();
+            let store = Arc::clone(&store);
+            let check_point = config.config_options.check_point;
+            let observer = Arc::clone(&self.observer);
+            let export_handle = thread::spawn(move || {
+                export_worker(check_point, &store, export_q_r, observer.as_ref());
+            });
+            export_handles.push(export_handle);
+        }
+
+        let (progress_sender, progress_receiver) = bounded(5);
+        let progress_observer = Arc::clone(&self.observer);
+        let progress_handle = thread::spawn(move || {
+            let mut processed = 0;
+            for _ in progress_receiver.iter() {
+                processed += 1;
+                progress_observer.on_progress(processed, total_files);
+            }
+            processed
+        });
+
+        let (io_q_s, io_q_r) = bounded(config.config_options.buffer_size);
+
+        match &config.config_options.buffer_path {
+            Some(buffer_path) => {
+                let buffer_path = PathBuf::from(buffer_path);
+                std::fs::create_dir_all(&buffer_path)?;
+                let buffer_path = std::fs::canonicalize(buffer_path)?;
+
+                let io_control = Arc::clone(control);
+                let io_handle = thread::spawn(move || {
+                    for file in file_paths.iter() {
+                        if !io_control.proceed() {
+                            break;
+                        }
+                        if let Err(e) = io::io_worker(&buffer_path, file, io_q_s.clone()) {
+                            error!("Buffer queue closed: {}", e);
+                            break;
+                        }
+                    }
+                    drop(io_q_s);
+                });
+
+                io_q_r.iter().par_bridge().for_each(|file| {
+                    if !control.proceed() {
+                        return;
+                    }
+                    let array_q_s = array_q_s.clone();
+                    media_worker(file, &media_config, array_q_s, progress_sender.clone());
+                });
+                if io_handle.join().is_err() {
+                    control.fail("Buffer worker panicked".to_string());
+                }
+            }
+            None => {
+                file_paths.par_iter().for_each(|file| {
+                    if !control.proceed() {
+                        return;
+                    }
+                    let array_q_s = array_q_s.clone();
+                    media_worker(
+                        file.clone(),
+                        &media_config,
+                        array_q_s,
+                        progress_sender.clone(),
+                    );
+                });
+            }
+        }
+
+        drop(array_q_s);
+        drop(progress_sender);
+
+        for d_handle in detect_handles {
+            // Worker errors are already reported to `control` by the worker itself
+            if let Err(e) = d_handle.join() {
+                error!("Error joining detect worker: {:?}", e);
+                control.fail("Detect worker panicked".to_string());
+            }
+        }
+
+        drop(export_q_s);
+
+        for e_handle in export_handles {
+            if let Err(e) = e_handle.join() {
+                error!("Error joining export worker: {:?}", e);
+                control.fail("Export worker panicked".to_string());
+            }
+        }
+
+        let processed_files = progress_handle.join().unwrap_or_default();
+        store.commit()?;
+        let (mut export_data, _) = read_store(&store_path)?;
+        for frame in export_data.iter_mut() {
+            if let Some(file) = indexed.get(&frame.file.file_path) {
+                frame.file = file.clone();
+            }
+        }
+        let frames = export_data.len();
+
+        let mut metadata = metadata;
+        metadata.info.end_time = Some(Local::now().to_rfc3339());
+        export(
+            &folder_path,
+            &mut export_data,
+            &metadata,
+            &config.config_options,
+        )?;
+
+        cleanup_buffer(&config.config_options.buffer_path)?;
+
+        // Results so far are flushed above, so the job can be resumed from them
+        if let Some(fatal_error) = control.fatal_error() {
+            return Err(anyhow!(fatal_error));
+        }
+
+        let cancelled = control.is_cancelled();
+        if cancelled {
+            log::info!("Detection cancelled after {:?}", start.elapsed());
+        } else {
+            log::info!("Elapsed time: {:?}", start.elapsed());
+        }
+
+        let summary = RunSummary {
+            total_files,
+            processed_files,
+            frames,
+            cancelled,
+            elapsed: start.elapsed(),
+            result_path: folder_path.join(config.config_options.export_format.file_name()),
+            store_path,
+        };
+        self.observer.on_complete(&summary);
+        Ok(summary)
+    }
+}
+
+fn cleanup_buffer(buffer_path: &Option<String>) -> Result<()> {
+    if let Some(path) = buffer_path {
+        let path = std::path::PathBuf::from(path);
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the store to go on with, dropping files it has all frames of. Other
+/// result files are copied into a new store.
+fn resume_from_checkpoint(
+    checkpoint_path: &str,
+    store_path: &Path,
+    all_files: &mut HashSet<FileItem>,
+    metadata: &RunMetadata,
+) -> Result<ResultStore> {
+    let checkpoint = Path::new(checkpoint_path);
+    if !checkpoint.exists() {
+        log::error!("Checkpoint file does not exist");
+        return Err(anyhow::anyhow!("Checkpoint file does not exist"));
+    }
+    if !checkpoint.is_file() {
+        log::error!("Checkpoint path is not a file");
+        return Err(anyhow::anyhow!("Checkpoint path is not a file"));
+    }
+    let store = match checkpoint.extension().and_then(|ext| ext.to_str()) {
+        Some("jsonl") if std::fs::canonicalize(checkpoint)? == store_path => {
+            let previous = StoreReader::open(checkpoint)?.metadata;
+            check_checkpoint_metadata(Some(&previous), metadata)?;
+            ResultStore::open(store_path)?
+        }
+        Some("json" | "csv" | "jsonl") => {
+            let (frames, previous) = read_result(checkpoint)?;
+            check_checkpoint_metadata(previous.as_ref(), metadata)?;
+            let store = ResultStore::create(store_path, metadata)?;
+            for frame in &frames {
+                store.insert(frame)?;
+            }
+            store.commit()?;
+            store
+        }
+        Some(ext) => {
+            log::error!("Invalid checkpoint file extension: {}", ext);
+            return Err(anyhow::anyhow!(
+                "Invalid checkpoint file extension: {}",
+                ext
+            ));
+        }
+        None => {
+            log::error!("Invalid checkpoint file extension");
+            return Err(anyhow::anyhow!("Invalid checkpoint file extension"));
+        }
+    };
+    let completed = completed_files(store_path)?;
+    all_files.retain(|file| !completed.contains(&file.file_path));
+    Ok(store)
+}
+
+/// Refuse to resume a checkpoint of another model, frames before and after the
+/// resume would not be comparable. Other option changes only warn.
+fn check_checkpoint_metadata(previous: Option<&RunMetadata>, current: &RunMetadata) -> Result<()> {
+    let Some(previous) = previous else {
+        log::warn!("Checkpoint has no run metadata, it may come from another model");
+        return Ok(());
+    };
+    let (before, now) = (&previous.info.model, &current.info.model);
+    let same_model = match (&before.md5, &now.md5) {
+        (Some(before_md5), Some(now_md5)) => before_md5.eq_ignore_ascii_case(now_md5),
+        _ => before.name == now.name,
+    };
+    if !same_model {
+        return Err(anyhow!(
+            "Checkpoint was made with model {} ({}), not {} ({})",
+            before.name,
+            before.md5.as_deref().unwrap_or("unknown md5"),
+            now.name,
+            now.md5.as_deref().unwrap_or("unknown md5")
+        ));
+    }
+
+    let (before, now) = (&previous.config, &current.config);
+    let thresholds = |c: &RunConfig| {
+        (
+            c.confidence_threshold,
+            c.iou_threshold,
+            c.class_thresholds.clone(),
+        )
+    };
+    if thresholds(before) != thresholds(now) {
+        log::warn!(
+            "Checkpoint was made with confidence {} and iou {} ({:?}), resuming with {} and {} ({:?})",
+            before.confidence_threshold,
+            before.iou_threshold,
+            before.class_thresholds,
+            now.confidence_threshold,
+            now.iou_threshold,
+            now.class_thresholds
+        );
+    }
+    let sampling = |c: &RunConfig| {
+        (
+            c.iframe_only,
+            c.max_frames,
+            c.video_sampling,
+            c.extensions.clone(),
+            c.tiling,
+        )
+    };
+    if sampling(before) != sampling(now) {
+        log::warn!(
+            "Checkpoint was made with other sampling options, frames of resumed files differ"
+        );
+    }
+    Ok(())
+}
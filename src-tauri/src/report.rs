@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+use crate::export::{parse_shoot_time, read_result, ExportFrame};
+use crate::megadetector::relative_path;
+use crate::organize::DEFAULT_CLASSES;
+
+/// Files of a report, written into the media folder unless told otherwise.
+pub const REPORT_CSV: &str = "events.csv";
+pub const REPORT_JSON: &str = "events.json";
+
+/// Minutes between independent detections of a class at a camera.
+pub const DEFAULT_INTERVAL: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ReportOptions {
+    /// A detection at least this many minutes after the last independent
+    /// detection of its class at the camera is a new independent event
+    pub interval: u64,
+    /// Count the best species of classified boxes instead of their class
+    pub species: bool,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            species: false,
+        }
+    }
+}
+
+/// Independent detections per camera and class of a result.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventReport {
+    /// Independence interval in minutes
+    pub interval: u64,
+    pub cameras: Vec<CameraReport>,
+}
+
+/// One folder with media, like the deployments of Camtrap DP.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CameraReport {
+    /// Folder relative to the media folder, the media folder's name for files
+    /// directly in it
+    pub camera: String,
+    /// RFC 3339 shoot time of the first and last file, the camera's effort
+    pub first_shot: Option<String>,
+    pub last_shot: Option<String>,
+    /// Calendar days from the first to the last shot, both included
+    pub effort_days: u32,
+    pub files: usize,
+    /// Files without a shoot time, left out of events and effort
+    pub undated: usize,
+    pub classes: Vec<ClassReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClassReport {
+    pub class: String,
+    /// Files with a box of the class
+    pub detections: usize,
+    /// Independent events among the dated detections
+    pub events: usize,
+    /// Independent events per 100 camera days, `None` without effort
+    pub rate: Option<f64>,
+}
+
+/// Shoot time and classes of one file, from all its frames.
+struct FileDetections<'a> {
+    camera: String,
+    time: Option<DateTime<FixedOffset>>,
+    classes: BTreeSet<&'a str>,
+}
+
+/// Number of independent events in sorted detection times.
+fn independent_events(times: &[DateTime<FixedOffset>], interval: Duration) -> usize {
+    let mut events = 0;
+    let mut last_event: Option<DateTime<FixedOffset>> = None;
+    for &time in times {
+        if last_event.is_none_or(|last| time - last >= interval) {
+            events += 1;
+            last_event = Some(time);
+        }
+    }
+    events
+}
+
+/// Count independent detections of each class at each camera. A file is one
+/// detection of every class it has a box of, at its earliest shoot time.
+pub fn event_report(
+    export_data: &[ExportFrame],
+    classes: &[String],
+    folder_path: &Path,
+    options: &ReportOptions,
+) -> EventReport {
+    let root_name = folder_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "media".to_string());
+    let mut files: BTreeMap<&Path, FileDetections> = BTreeMap::new();
+    for frame in export_data {
+        let path = frame.file.file_path.as_path();
+        let file = files.entry(path).or_insert_with(|| {
+            let folder = path.parent().unwrap_or(folder_path);
+            let camera = match relative_path(folder, folder_path).as_str() {
+                "" => root_name.clone(),
+                camera => camera.to_string(),
+            };
+            FileDetections {
+                camera,
+                time: None,
+                classes: BTreeSet::new(),
+            }
+        });
+        if let Some(time) = frame.shoot_time.as_deref().and_then(parse_shoot_time) {
+            file.time = Some(file.time.map_or(time, |earliest| earliest.min(time)));
+        }
+        for bbox in frame.bboxes.iter().flatten() {
+            let species = bbox
+                .species
+                .as_ref()
+                .and_then(|species| species.first())
+                .filter(|_| options.species);
+            let class = match species {
+                Some(species) => species.label.as_str(),
+                None => match classes.get(bbox.class) {
+                    Some(class) => class.as_str(),
+                    None => continue,
+                },
+            };
+            file.classes.insert(class);
+        }
+    }
+
+    let mut cameras: BTreeMap<&str, Vec<&FileDetections>> = BTreeMap::new();
+    for file in files.values() {
+        cameras.entry(&file.camera).or_default().push(file);
+    }
+    let interval = Duration::minutes(options.interval as i64);
+    let cameras = cameras
+        .into_iter()
+        .map(|(camera, files)| {
+            let times: Vec<DateTime<FixedOffset>> =
+                files.iter().filter_map(|file| file.time).collect();
+            let first = times.iter().min();
+            let last = times.iter().max();
+            let effort_days = match (first, last) {
+                (Some(first), Some(last)) => {
+                    (last.date_naive() - first.date_naive()).num_days() as u32 + 1
+                }
+                _ => 0,
+            };
+
+            let mut detections: BTreeMap<&str, (usize, Vec<DateTime<FixedOffset>>)> =
+                BTreeMap::new();
+            for file in &files {
+                for &class in &file.classes {
+                    let (count, times) = detections.entry(class).or_default();
+                    *count += 1;
+                    times.extend(file.time);
+                }
+            }
+            let classes = detections
+                .into_iter()
+                .map(|(class, (detections, mut times))| {
+                    times.sort();
+                    let events = independent_events(&times, interval);
+                    ClassReport {
+                        class: class.to_string(),
+                        detections,
+                        events,
+                        rate: (effort_days > 0).then(|| events as f64 / effort_days as f64 * 100.0),
+                    }
+                })
+                .collect();
+
+            CameraReport {
+                camera: camera.to_string(),
+                first_shot: first.map(DateTime::to_rfc3339),
+                last_shot: last.map(DateTime::to_rfc3339),
+                effort_days,
+                files: files.len(),
+                undated: files.len() - times.len(),
+                classes,
+            }
+        })
+        .collect();
+
+    EventReport {
+        interval: options.interval,
+        cameras,
+    }
+}
+
+/// Report of a result file or store in the media folder.
+pub fn report<P: AsRef<Path>>(result_path: P, options: &ReportOptions) -> Result<EventReport> {
+    let result_path = std::fs::canonicalize(result_path)?;
+    let folder_path = result_path
+        .parent()
+        .ok_or_else(|| anyhow!("Result file has no parent folder"))?;
+    let (export_data, metadata) = read_result(&result_path)?;
+    let classes = match metadata {
+        Some(metadata) => metadata.info.model.classes,
+        None => {
+            log::warn!("Result has no model classes, assuming MegaDetector classes");
+            DEFAULT_CLASSES.map(String::from).to_vec()
+        }
+    };
+    Ok(event_report(&export_data, &classes, folder_path, options))
+}
+
+/// Write `events.json` and `events.csv`, one row per camera and class. A
+/// camera without detections has a row without class for its effort.
+pub fn write_report(report: &EventReport, dir: &Path) -> Result<()> {
+    std::fs::write(dir.join(REPORT_JSON), serde_json::to_string_pretty(report)?)?;
+    let mut wtr = csv::Writer::from_path(dir.join(REPORT_CSV))?;
+    wtr.write_record([
+        "camera",
+        "class",
+        "first_shot",
+        "last_shot",
+        "effort_days",
+        "files",
+        "undated",
+        "detections",
+        "independent_events",
+        "events_per_100_days",
+    ])?;
+    for camera in &report.cameras {
+        let row = |class: Option<&ClassReport>| {
+            [
+                camera.camera.clone(),
+                class.map(|class| class.class.clone()).unwrap_or_default(),
+                camera.first_shot.clone().unwrap_or_default(),
+                camera.last_shot.clone().unwrap_or_default(),
+                camera.effort_days.to_string(),
+                camera.files.to_string(),
+                camera.undated.to_string(),
+                class
+                    .map(|class| class.detections.to_string())
+                    .unwrap_or_default(),
+                class
+                    .map(|class| class.events.to_string())
+                    .unwrap_or_default(),
+                class
+                    .and_then(|class| class.rate)
+                    .map(|rate| format!("{:.2}", rate))
+                    .unwrap_or_default(),
+            ]
+        };
+        if camera.classes.is_empty() {
+            wtr.write_record(row(None))?;
+        }
+        for class in &camera.classes {
+            wtr.write_record(row(Some(class)))?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::export::tests::{still, test_metadata, TempDir};
+
+    #[test]
+    fn test_event_report() {
+        let frames = vec![
+            still(
+                "/data/cam1/a.jpg",
+                Some("2024-05-01 12:00:00 +08:00"),
+                Some(0),
+            ),
+            still(
+                "/data/cam1/b.jpg",
+                Some("2024-05-01 12:20:00 +08:00"),
+                Some(0),
+            ),
+            // 40 minutes after the event of a, although 20 after b
+            still(
+                "/data/cam1/c.jpg",
+                Some("2024-05-01 12:40:00 +08:00"),
+                Some(0),
+            ),
+            still(
+                "/data/cam1/d.jpg",
+                Some("2024-05-02 08:00:00 +08:00"),
+                Some(1),
+            ),
+            still("/data/cam1/e.jpg", None, Some(0)),
+            still("/data/f.jpg", Some("2024-05-01 09:00:00 +08:00"), None),
+        ];
+        let classes = test_metadata().info.model.classes;
+        let report = event_report(
+            &frames,
+            &classes,
+            Path::new("/data"),
+            &ReportOptions::default(),
+        );
+        assert_eq!(report.cameras.len(), 2);
+        let cam1 = &report.cameras[0];
+        assert_eq!((cam1.camera.as_str(), cam1.effort_days), ("cam1", 2));
+        assert_eq!((cam1.files, cam1.undated), (5, 1));
+        let animal = &cam1.classes[0];
+        assert_eq!((animal.class.as_str(), animal.detections), ("Animal", 4));
+        assert_eq!(animal.events, 2);
+        assert_eq!(animal.rate, Some(100.0));
+        assert_eq!(cam1.classes[1].events, 1);
+        let root = &report.cameras[1];
+        assert_eq!((root.camera.as_str(), root.effort_days), ("data", 1));
+        assert!(root.classes.is_empty());
+
+        let dir = TempDir::new("report");
+        write_report(&report, dir.path()).unwrap();
+        let csv = std::fs::read_to_string(dir.path().join(REPORT_CSV)).unwrap();
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.contains("cam1,Animal,2024-05-01T12:00:00+08:00"));
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::export::{parse_shoot_time, ExportFrame};
+use crate::megadetector::relative_path;
+
+/// Stills of a folder at most this many seconds apart are one sequence.
+pub const DEFAULT_SEQUENCE_GAP: u64 = 60;
+
+/// Stills without a shoot time are grouped this many consecutive file numbers at a time.
+pub const DEFAULT_BURST: usize = 3;
+
+/// How shots are grouped into sequences, the shots of one trigger of a camera.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SequenceOptions {
+    /// Seconds between stills of one sequence
+    pub gap: u64,
+    /// Gaps of folders relative to the media folder, like `site1/cam2`, for
+    /// cameras with other trigger settings. Subfolders use the gap of their
+    /// closest listed parent
+    pub folder_gaps: BTreeMap<String, u64>,
+    /// Stills without a shoot time are grouped by the number in their name,
+    /// `IMG_0001.JPG` to `IMG_0003.JPG` is one sequence. 1 keeps them apart
+    pub burst: usize,
+}
+
+impl Default for SequenceOptions {
+    fn default() -> Self {
+        Self {
+            gap: DEFAULT_SEQUENCE_GAP,
+            folder_gaps: BTreeMap::new(),
+            burst: DEFAULT_BURST,
+        }
+    }
+}
+
+impl SequenceOptions {
+    /// Gap of a folder relative to the media folder.
+    pub fn folder_gap(&self, folder: &str) -> u64 {
+        let mut folder = Some(Path::new(folder));
+        while let Some(current) = folder {
+            let key = current.to_string_lossy().replace('\\', "/");
+            if let Some(&gap) = self.folder_gaps.get(&key) {
+                return gap;
+            }
+            folder = current.parent();
+        }
+        self.gap
+    }
+}
+
+/// Name of a file split at its trailing number, `IMG_0001.JPG` is `("IMG_", 1)`.
+fn file_number(file: &Path) -> Option<(String, u64)> {
+    let stem = file.file_stem()?.to_str()?;
+    let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+    let number = stem[prefix.len()..].parse().ok()?;
+    Some((prefix.to_string(), number))
+}
+
+/// Sequence id of each frame, unique within the result. The frames of a video
+/// are one sequence. Stills are grouped per folder by shoot time, stills
+/// without one by the numbers in their names.
+fn group(
+    export_data: &[&ExportFrame],
+    folder_path: &Path,
+    options: &SequenceOptions,
+) -> Vec<String> {
+    let mut timed: BTreeMap<usize, (u64, Vec<(i64, usize)>)> = BTreeMap::new();
+    let mut numbered: BTreeMap<(usize, String, String), Vec<(u64, usize)>> = BTreeMap::new();
+    // First frame of each file, the other frames join its sequence
+    let mut firsts: HashMap<&Path, usize> = HashMap::new();
+    let mut seq_ids = vec![String::new(); export_data.len()];
+    let mut next_seq = HashMap::new();
+    let mut new_seq = |folder_id: usize| {
+        let n = next_seq.entry(folder_id).or_insert(0);
+        *n += 1;
+        format!("{}-{}", folder_id, n)
+    };
+
+    for (i, frame) in export_data.iter().enumerate() {
+        let file = frame.file.file_path.as_path();
+        if firsts.contains_key(file) {
+            continue;
+        }
+        firsts.insert(file, i);
+        let folder_id = frame.file.folder_id;
+        if frame.video_fps.is_some() || frame.total_frames > 1 {
+            seq_ids[i] = new_seq(folder_id);
+            continue;
+        }
+        if let Some(time) = frame.shoot_time.as_deref().and_then(parse_shoot_time) {
+            let (_, shots) = timed.entry(folder_id).or_insert_with(|| {
+                let folder = file.parent().unwrap_or(folder_path);
+                (
+                    options.folder_gap(&relative_path(folder, folder_path)),
+                    Vec::new(),
+                )
+            });
+            shots.push((time.timestamp(), i));
+            continue;
+        }
+        match file_number(file) {
+            Some((prefix, number)) => {
+                let ext = file
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                numbered
+                    .entry((folder_id, prefix, ext))
+                    .or_default()
+                    .push((number, i));
+            }
+            None => seq_ids[i] = new_seq(folder_id),
+        }
+    }
+
+    for (folder_id, (gap, mut shots)) in timed {
+        shots.sort();
+        let mut last = None;
+        let mut seq = String::new();
+        for (time, i) in shots {
+            if last.is_none_or(|last| time - last > gap as i64) {
+                seq = new_seq(folder_id);
+            }
+            last = Some(time);
+            seq_ids[i] = seq.clone();
+        }
+    }
+    // Burst shots are numbered one after another, a run of numbers is split
+    // into bursts as the camera can't be asked where one ends
+    for ((folder_id, _, _), mut shots) in numbered {
+        shots.sort();
+        let mut last = None;
+        let mut seq = String::new();
+        let mut seq_len = 0;
+        for (number, i) in shots {
+            if last.is_none_or(|last| number != last + 1) || seq_len >= options.burst.max(1) {
+                seq = new_seq(folder_id);
+                seq_len = 0;
+            }
+            last = Some(number);
+            seq_len += 1;
+            seq_ids[i] = seq.clone();
+        }
+    }
+
+    for (i, frame) in export_data.iter().enumerate() {
+        let first = firsts[frame.file.file_path.as_path()];
+        if first != i {
+            seq_ids[i] = seq_ids[first].clone();
+        }
+    }
+    seq_ids
+}
+
+/// `(sequence_id, frame_num, seq_num_frames)` of each frame, in `export_data`
+/// order. Frames keep the sequences recorded in the result unless `options`
+/// regroups them, results without recorded sequences are grouped with the
+/// default options.
+pub fn sequences(
+    export_data: &[&ExportFrame],
+    folder_path: &Path,
+    options: Option<&SequenceOptions>,
+) -> Vec<(String, usize, usize)> {
+    let recorded = options.is_none() && export_data.iter().all(|f| f.sequence_id.is_some());
+    let seq_ids = if recorded {
+        export_data
+            .iter()
+            .map(|frame| frame.sequence_id.clone().unwrap_or_default())
+            .collect()
+    } else {
+        let options = options.cloned().unwrap_or_default();
+        group(export_data, folder_path, &options)
+    };
+
+    let mut seq_frames: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, seq) in seq_ids.iter().enumerate() {
+        seq_frames.entry(seq.as_str()).or_default().push(i);
+    }
+    let mut frame_num = vec![0; export_data.len()];
+    let mut seq_num_frames = vec![0; export_data.len()];
+    for frames in seq_frames.values_mut() {
+        frames.sort_by_key(|&i| {
+            let frame = export_data[i];
+            (
+                frame.shoot_time.as_deref().and_then(parse_shoot_time),
+                &frame.file.file_path,
+                frame.frame_index,
+            )
+        });
+        for (n, &i) in frames.iter().enumerate() {
+            frame_num[i] = n;
+            seq_num_frames[i] = frames.len();
+        }
+    }
+    seq_ids
+        .into_iter()
+        .enumerate()
+        .map(|(i, seq)| (seq, frame_num[i], seq_num_frames[i]))
+        .collect()
+}
+
+/// Group the frames of a result and record the sequence of each in `sequence_id`.
+pub fn assign_sequences(
+    export_data: &mut [ExportFrame],
+    folder_path: &Path,
+    options: &SequenceOptions,
+) {
+    let frames: Vec<&ExportFrame> = export_data.iter().collect();
+    let seq_ids = group(&frames, folder_path, options);
+    for (frame, seq) in export_data.iter_mut().zip(seq_ids) {
+        frame.sequence_id = Some(seq);
+    }
+}
+
+/// The strongest, lowest, value of each sequence for each of its frames, in
+/// the order of `sequences`. Frames without a value take the one of their
+/// sequence, a sequence without any value leaves them `None`.
+pub fn propagate_strongest<T: Ord + Copy>(
+    sequences: &[(String, usize, usize)],
+    values: &[Option<T>],
+) -> Vec<Option<T>> {
+    let mut strongest: HashMap<&str, T> = HashMap::new();
+    for ((seq, _, _), value) in sequences.iter().zip(values) {
+        if let Some(value) = *value {
+            strongest
+                .entry(seq)
+                .and_modify(|best| *best = (*best).min(value))
+                .or_insert(value);
+        }
+    }
+    sequences
+        .iter()
+        .map(|(seq, _, _)| strongest.get(seq.as_str()).copied())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::export::tests::{still, test_frame};
+
+    #[test]
+    fn test_video_sequence() {
+        let mut second = test_frame();
+        second.frame_index = 4;
+        let frames = [&test_frame(), &second];
+        let sequences = sequences(&frames, Path::new("/data"), None);
+        assert_eq!(sequences[0].0, sequences[1].0);
+        assert_eq!((sequences[0].1, sequences[1].1), (0, 1));
+        assert_eq!(sequences[1].2, 2);
+    }
+
+    #[test]
+    fn test_sequences() {
+        let mut frames = vec![
+            still(
+                "/data/cam1/a.jpg",
+                Some("2024-05-01 12:00:00 +08:00"),
+                Some(0),
+            ),
+            still(
+                "/data/cam1/b.jpg",
+                Some("2024-05-01 12:01:30 +08:00"),
+                Some(0),
+            ),
+            still("/data/cam1/IMG_0001.JPG", None, Some(0)),
+            still("/data/cam1/IMG_0002.JPG", None, Some(0)),
+            still("/data/cam1/IMG_0003.JPG", None, Some(0)),
+            still("/data/cam1/IMG_0004.JPG", None, Some(0)),
+            still("/data/cam1/IMG_0009.JPG", None, Some(0)),
+        ];
+        let options = SequenceOptions::default();
+        assign_sequences(&mut frames, Path::new("/data"), &options);
+        let ids: Vec<&str> = frames
+            .iter()
+            .map(|frame| frame.sequence_id.as_deref().unwrap())
+            .collect();
+        // 90 seconds apart, a burst of 3, the next number and a gap in numbering
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids[2], ids[3]);
+        assert_eq!(ids[3], ids[4]);
+        assert_ne!(ids[4], ids[5]);
+        assert_ne!(ids[5], ids[6]);
+
+        let options = SequenceOptions {
+            folder_gaps: BTreeMap::from([("cam1".to_string(), 120)]),
+            ..Default::default()
+        };
+        let refs: Vec<&ExportFrame> = frames.iter().collect();
+        let regrouped = sequences(&refs, Path::new("/data"), Some(&options));
+        assert_eq!(regrouped[0].0, regrouped[1].0);
+        assert_eq!((regrouped[1].1, regrouped[1].2), (1, 2));
+        // Recorded sequences are kept without options
+        assert_eq!(sequences(&refs, Path::new("/data"), None)[1].0, ids[1]);
+
+        let ranks = [Some(2), Some(0), None, None, None, Some(1), None];
+        let strongest = propagate_strongest(&regrouped, &ranks);
+        assert_eq!(&strongest[..3], &[Some(0), Some(0), None]);
+        assert_eq!(strongest[5], Some(1));
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+
+use crate::export::{ExportError, ExportFrame, RunMetadata};
+
+/// Result store of a job, written into the media folder while detecting.
+pub const STORE_FILE: &str = "result.jsonl";
+
+/// Append-only store of detected frames. The first line is the [`RunMetadata`]
+/// of the run that created it, every other line is one [`ExportFrame`].
+///
+/// Frames are appended as they arrive, a checkpoint is a [`commit`](Self::commit)
+/// of what was appended so far. `result.json` and the other formats are
+/// generated from the store once the job ends.
+pub struct ResultStore {
+    path: PathBuf,
+    writer: Mutex<BufWriter<File>>,
+    inserted: AtomicUsize,
+}
+
+impl ResultStore {
+    /// Create an empty store, replacing one left by an earlier run.
+    pub fn create<P: AsRef<Path>>(path: P, metadata: &RunMetadata) -> Result<Self, ExportError> {
+        let path = path.as_ref().to_path_buf();
+        let mut writer = BufWriter::new(File::create(&path)?);
+        serde_json::to_writer(&mut writer, metadata)?;
+        writer.write_all(b"\n")?;
+        let store = ResultStore {
+            path,
+            writer: Mutex::new(writer),
+            inserted: AtomicUsize::new(0),
+        };
+        store.commit()?;
+        Ok(store)
+    }
+
+    /// Open an existing store to append to it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, ExportError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new().read(true).append(true).open(&path)?;
+        // Appending to a line torn by a crash would corrupt the next frame too
+        let len = file.metadata()?.len();
+        let complete = complete_lines_len(&mut file)?;
+        if complete < len {
+            log::warn!(
+                "Dropping {} bytes of an incomplete frame at the end of {}",
+                len - complete,
+                path.display()
+            );
+            file.set_len(complete)?;
+        }
+        Ok(ResultStore {
+            path,
+            writer: Mutex::new(BufWriter::new(file)),
+            inserted: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a frame, returns the number of frames appended by this run.
+    pub fn insert(&self, frame: &ExportFrame) -> Result<usize, ExportError> {
+        let mut line = serde_json::to_vec(frame)?;
+        line.push(b'\n');
+        let mut writer = self.writer.lock().unwrap();
+        writer.write_all(&line)?;
+        Ok(self.inserted.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// Make the frames appended so far durable.
+    pub fn commit(&self) -> Result<(), ExportError> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Length of the file up to and including its last newline.
+fn complete_lines_len(file: &mut File) -> std::io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut buf = [0u8; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(i) = chunk.iter().rposition(|&b| b == b'\n') {
+            return Ok(start + i as u64 + 1);
+        }
+        end = start;
+    }
+    Ok(0)
+}
+
+/// Streams the frames of a store without loading all of them.
+pub struct StoreReader {
+    path: PathBuf,
+    reader: BufReader<File>,
+    line: usize,
+    pub metadata: RunMetadata,
+}
+
+impl StoreReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let metadata = serde_json::from_str(&header)
+            .map_err(|e| anyhow!("{} is not a result store: {}", path.display(), e))?;
+        Ok(StoreReader {
+            path,
+            reader,
+            line: 1,
+            metadata,
+        })
+    }
+}
+
+impl Iterator for StoreReader {
+    type Item = Result<ExportFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e.into())),
+        }
+        self.line += 1;
+        match serde_json::from_str(&line) {
+            Ok(frame) => Some(Ok(frame)),
+            // Only the last line can be cut short, by a crash while appending
+            Err(_) if !line.ends_with('\n') => {
+                log::warn!(
+                    "Ignoring an incomplete frame at the end of {}",
+                    self.path.display()
+                );
+                None
+            }
+            Err(e) => Some(Err(anyhow!(
+                "Invalid frame on line {} of {}: {}",
+                self.line,
+                self.path.display(),
+                e
+            ))),
+        }
+    }
+}
+
+/// Frames and metadata of a store. A frame detected again after resuming
+/// replaces the earlier one.
+pub fn read_store<P: AsRef<Path>>(path: P) -> Result<(Vec<ExportFrame>, Option<RunMetadata>)> {
+    let mut reader = StoreReader::open(path)?;
+    let metadata = reader.metadata.clone();
+    let mut frames: Vec<ExportFrame> = Vec::new();
+    let mut positions: HashMap<(PathBuf, usize), usize> = HashMap::new();
+    for frame in &mut reader {
+        let frame = frame?;
+        let key = (frame.file.file_path.clone(), frame.frame_index);
+        match positions.get(&key) {
+            Some(&position) => frames[position] = frame,
+            None => {
+                positions.insert(key, frames.len());
+                frames.push(frame);
+            }
+        }
+    }
+    Ok((frames, Some(metadata)))
+}
+
+/// Files of a store with all their frames detected. A video that decoded to
+/// fewer frames than counted records the real number on its last frame, so the
+/// smallest `total_frames` of a file counts.
+pub fn completed_files<P: AsRef<Path>>(path: P) -> Result<HashSet<PathBuf>> {
+    let mut frames: HashMap<PathBuf, (usize, HashSet<usize>)> = HashMap::new();
+    for frame in StoreReader::open(path)? {
+        let frame = frame?;
+        let (total_frames, indices) = frames
+            .entry(frame.file.file_path)
+            .or_insert((usize::MAX, HashSet::new()));
+        *total_frames = (*total_frames).min(frame.total_frames);
+        indices.insert(frame.frame_index);
+    }
+    Ok(frames
+        .into_iter()
+        .filter(|(_, (total_frames, indices))| indices.len() >= *total_frames)
+        .map(|(file_path, _)| file_path)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::export::tests::{test_frame, test_metadata, TempDir};
+
+    #[test]
+    fn test_store_resume() {
+        let tmp = TempDir::new("store");
+        let dir = tmp.path();
+        let path = dir.join(STORE_FILE);
+
+        let mut frames: Vec<ExportFrame> = (0..3)
+            .map(|i| {
+                let mut frame = test_frame();
+                frame.frame_index = i;
+                frame.total_frames = 3;
+                frame
+            })
+            .collect();
+        let store = ResultStore::create(&path, &test_metadata()).unwrap();
+        assert_eq!(store.insert(&frames[0]).unwrap(), 1);
+        assert_eq!(store.insert(&frames[1]).unwrap(), 2);
+        store.commit().unwrap();
+        drop(store);
+        assert!(completed_files(&path).unwrap().is_empty());
+
+        // A crash in the middle of a frame
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"folder_id\":0,\"fi").unwrap();
+        drop(file);
+        assert_eq!(read_store(&path).unwrap().0.len(), 2);
+
+        let store = ResultStore::open(&path).unwrap();
+        frames[1].bboxes = Some(Vec::new());
+        store.insert(&frames[1]).unwrap();
+        store.insert(&frames[2]).unwrap();
+        store.commit().unwrap();
+
+        let (read, metadata) = read_store(&path).unwrap();
+        assert_eq!(read.len(), 3);
+        assert_eq!(read[1].bboxes.as_ref().map(Vec::len), Some(0));
+        assert_eq!(metadata.unwrap().info.model.classes.len(), 3);
+        assert!(completed_files(&path)
+            .unwrap()
+            .contains(&frames[0].file.file_path));
+
+        // A video that decoded to fewer frames records the count on its last one
+        let store = ResultStore::open(&path).unwrap();
+        let short: Vec<ExportFrame> = frames[..2]
+            .iter()
+            .cloned()
+            .map(|mut frame| {
+                frame.file.file_path = dir.join("short.mp4");
+                frame
+            })
+            .collect();
+        store.insert(&short[0]).unwrap();
+        let mut last = short[1].clone();
+        last.total_frames = 2;
+        store.insert(&last).unwrap();
+        store.commit().unwrap();
+        assert!(completed_files(&path)
+            .unwrap()
+            .contains(&dir.join("short.mp4")));
+    }
+}
@@ -0,0 +1,246 @@
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset};
+use thiserror::Error;
+
+/// Layout of organize without a template, a class folder next to each file.
+pub const DEFAULT_TEMPLATE: &str = "{folder}/{class}/{filename}";
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Value of fields that are unknown for a file, like `{date}` without a shoot time.
+const UNKNOWN: &str = "unknown";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TemplateError {
+    #[error("Unclosed {{ in template")]
+    Unclosed,
+
+    #[error("Unknown template field {{{0}}}")]
+    UnknownField(String),
+
+    #[error("Invalid date format {0}")]
+    InvalidDateFormat(String),
+
+    #[error("Template needs {{filename}} or {{stem}}, files would overwrite each other")]
+    NoFileName,
+
+    #[error("Template must stay inside the media folder")]
+    OutsideFolder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Class,
+    Species,
+    /// Folder of the file relative to the media folder
+    Folder,
+    /// First folder below the media folder
+    Site,
+    /// Name of the folder the file is in
+    Parent,
+    FileName,
+    Stem,
+    Extension,
+    Date(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Field(Field),
+}
+
+/// Destination of an organized file relative to the media folder, like
+/// `{class}/{site}/{date:%Y-%m}/{filename}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    segments: Vec<Segment>,
+}
+
+/// What a template is evaluated with for one file.
+pub struct TemplateContext<'a> {
+    pub class: &'a str,
+    /// Best species of the file's boxes, `{species}` is the class without one
+    pub species: Option<&'a str>,
+    /// Folder of the file relative to the media folder
+    pub folder: &'a Path,
+    pub file: &'a Path,
+    pub shoot_time: Option<DateTime<FixedOffset>>,
+}
+
+impl FromStr for PathTemplate {
+    type Err = TemplateError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or(TemplateError::Unclosed)? + start;
+            let field = &rest[start + 1..end];
+            let (name, format) = match field.split_once(':') {
+                Some((name, format)) => (name, Some(format)),
+                None => (field, None),
+            };
+            let field = match (name, format) {
+                ("class", None) => Field::Class,
+                ("species", None) => Field::Species,
+                ("folder", None) => Field::Folder,
+                ("site", None) => Field::Site,
+                ("parent", None) => Field::Parent,
+                ("filename", None) => Field::FileName,
+                ("stem", None) => Field::Stem,
+                ("ext", None) => Field::Extension,
+                ("date", format) => {
+                    let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+                    // chrono panics when displaying an invalid format
+                    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                        return Err(TemplateError::InvalidDateFormat(format.to_string()));
+                    }
+                    Field::Date(format.to_string())
+                }
+                _ => return Err(TemplateError::UnknownField(field.to_string())),
+            };
+            segments.push(Segment::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        if !segments
+            .iter()
+            .any(|s| matches!(s, Segment::Field(Field::FileName | Field::Stem)))
+        {
+            return Err(TemplateError::NoFileName);
+        }
+        let escapes = segments.iter().any(|s| match s {
+            Segment::Text(text) => text.split(['/', '\\']).any(|part| part == ".."),
+            Segment::Field(_) => false,
+        });
+        if escapes || template.starts_with(['/', '\\']) {
+            return Err(TemplateError::OutsideFolder);
+        }
+        Ok(PathTemplate { segments })
+    }
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+/// A value that is one path component, separators in class or species names
+/// would add folders.
+fn component(value: &str) -> String {
+    match value.replace(['/', '\\'], "_").as_str() {
+        "" | "." | ".." => "_".to_string(),
+        value => value.to_string(),
+    }
+}
+
+impl PathTemplate {
+    /// Destination relative to the media folder. Fails when a value, like a
+    /// date format with `..`, would take it out of the media folder.
+    pub fn render(&self, context: &TemplateContext) -> Result<PathBuf, TemplateError> {
+        let file_part = |part: Option<&std::ffi::OsStr>| {
+            part.map(|part| part.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let mut rendered = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Field(field) => rendered.push_str(&match field {
+                    Field::Class => component(context.class),
+                    Field::Species => component(context.species.unwrap_or(context.class)),
+                    Field::Folder => context.folder.to_string_lossy().replace('\\', "/"),
+                    Field::Site => context
+                        .folder
+                        .components()
+                        .next()
+                        .map(|site| component(&site.as_os_str().to_string_lossy()))
+                        .unwrap_or_default(),
+                    Field::Parent => context
+                        .folder
+                        .file_name()
+                        .map(|parent| component(&parent.to_string_lossy()))
+                        .unwrap_or_default(),
+                    Field::FileName => file_part(context.file.file_name()),
+                    Field::Stem => file_part(context.file.file_stem()),
+                    Field::Extension => file_part(context.file.extension()),
+                    // A format like %Y/%m nests folders
+                    Field::Date(format) => match context.shoot_time {
+                        Some(time) => time.format(format).to_string(),
+                        None => UNKNOWN.to_string(),
+                    },
+                }),
+            }
+        }
+        // Fields that are empty for files in the media folder itself leave empty components
+        let path: PathBuf = rendered
+            .split(['/', '\\'])
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect();
+        // A `..` or a drive like `C:` from a field would leave the media folder
+        if path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(TemplateError::OutsideFolder);
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::export::parse_shoot_time;
+
+    #[test]
+    fn test_render_template() {
+        let context = TemplateContext {
+            class: "Animal",
+            species: Some("Sus scrofa"),
+            folder: Path::new("site1/cam2"),
+            file: Path::new("/data/site1/cam2/IMG_0001.JPG"),
+            shoot_time: parse_shoot_time("2024-05-01 12:00:03 +08:00"),
+        };
+        let template: PathTemplate = "{class}/{site}/{date:%Y-%m}/{filename}".parse().unwrap();
+        assert_eq!(
+            template.render(&context),
+            Ok(PathBuf::from("Animal/site1/2024-05/IMG_0001.JPG"))
+        );
+        assert_eq!(
+            PathTemplate::default().render(&TemplateContext {
+                folder: Path::new(""),
+                ..context
+            }),
+            Ok(PathBuf::from("Animal/IMG_0001.JPG"))
+        );
+        let escaping: PathTemplate = "{date:../../%Y}/{filename}".parse().unwrap();
+        assert_eq!(escaping.render(&context), Err(TemplateError::OutsideFolder));
+
+        assert_eq!(
+            "{class}/{file}".parse::<PathTemplate>(),
+            Err(TemplateError::UnknownField("file".to_string()))
+        );
+        assert_eq!(
+            "{class}/".parse::<PathTemplate>(),
+            Err(TemplateError::NoFileName)
+        );
+        assert_eq!(
+            "../{class}/{filename}".parse::<PathTemplate>(),
+            Err(TemplateError::OutsideFolder)
+        );
+        assert!("{date:%Q}/{filename}".parse::<PathTemplate>().is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use image::RgbImage;
+use ndarray::{s, Array3};
+use serde::{Deserialize, Serialize};
+
+use crate::adapter::ModelInput;
+
+/// Sliced inference of stills larger than the model input.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Tiling {
+    /// Overlap of neighbouring tiles as a fraction of the tile size
+    pub overlap: f32,
+    /// Also run the letterboxed whole frame, which catches animals larger than a tile
+    pub full_frame: bool,
+}
+
+impl Default for Tiling {
+    fn default() -> Self {
+        Self {
+            overlap: 0.2,
+            full_frame: true,
+        }
+    }
+}
+
+/// A model sized slice of the original image at full resolution.
+pub struct Tile {
+    /// `[3, size, size]` 0–1 RGB, padded right and bottom at image borders
+    pub data: Array3<f32>,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    pub fn input(&self) -> ModelInput<'_> {
+        ModelInput {
+            data: self.data.view(),
+            width: self.width,
+            height: self.height,
+            padding: (0, 0),
+            ratio: 1.0,
+        }
+    }
+}
+
+/// Start offsets of tiles of `size` covering `length`, the last one aligned to the end.
+fn tile_starts(length: usize, size: usize, overlap: f32) -> Vec<usize> {
+    if length <= size {
+        return vec![0];
+    }
+    let step = ((size as f32 * (1.0 - overlap)).round() as usize).max(1);
+    let mut starts: Vec<usize> = (0..)
+        .step_by(step)
+        .take_while(|start| start + size < length)
+        .collect();
+    starts.push(length - size);
+    starts
+}
+
+/// Slice an image into overlapping `size` tiles. Images that already fit the
+/// model input give no tiles.
+pub fn make_tiles(
+    image: &RgbImage,
+    size: usize,
+    tiling: &Tiling,
+    pad_color: [f32; 3],
+) -> Vec<Tile> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width <= size && height <= size {
+        return Vec::new();
+    }
+
+    let mut tiles = Vec::new();
+    for y in tile_starts(height, size, tiling.overlap) {
+        for x in tile_starts(width, size, tiling.overlap) {
+            let (tile_w, tile_h) = (size.min(width - x), size.min(height - y));
+            let mut data = Array3::<f32>::zeros((3, size, size));
+            for (c, &value) in pad_color.iter().enumerate() {
+                data.slice_mut(s![c, .., ..]).fill(value);
+            }
+            for ty in 0..tile_h {
+                for tx in 0..tile_w {
+                    let pixel = image.get_pixel((x + tx) as u32, (y + ty) as u32);
+                    for c in 0..3 {
+                        data[[c, ty, tx]] = pixel[c] as f32 / 255.0;
+                    }
+                }
+            }
+            tiles.push(Tile {
+                data,
+                x,
+                y,
+                width: tile_w,
+                height: tile_h,
+            });
+        }
+    }
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_starts() {
+        assert_eq!(tile_starts(500, 640, 0.2), vec![0]);
+        // Step of 512, last tile aligned to the right edge
+        assert_eq!(tile_starts(1920, 640, 0.2), vec![0, 512, 1024, 1280]);
+        assert_eq!(tile_starts(1280, 640, 0.0), vec![0, 640]);
+    }
+}
@@ -2,9 +2,14 @@ use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
+use md5::{Digest, Md5};
 use serde::{Deserialize, Serialize};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::adapter::{Architecture, Preprocess, TensorConfig};
+use crate::media::MediaExtensions;
+use crate::organize::organized_files;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Bbox {
     pub x1: f32,
@@ -13,6 +18,15 @@ pub struct Bbox {
     pub y2: f32,
     pub score: f32,
     pub class: usize,
+    /// Top-k species of the second stage classifier, best first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub species: Option<Vec<SpeciesScore>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpeciesScore {
+    pub label: String,
+    pub score: f32,
 }
 
 impl Bbox {
@@ -160,21 +174,36 @@ impl FileItem {
 }
 
 fn is_skip(entry: &DirEntry) -> bool {
-    let skip_dirs = ["Animal", "Person", "Vehicle", "Blank"];
+    let skip_dirs = ["Animal", "Person", "Vehicle", "Blank", "camtrap-dp"];
     entry
         .file_name()
         .to_str()
         .map(|s| {
-            skip_dirs.contains(&s) || s.starts_with('.') || s == "result.csv" || s == "result.json"
+            skip_dirs.contains(&s)
+                || s.starts_with('.')
+                || s == "result.csv"
+                || s == "result.json"
+                || s == "result.jsonl"
+                || s == "result.meta.json"
+                || s == "result.megadetector.json"
+                || s == "result.coco.json"
         })
         .unwrap_or(false)
 }
 
-pub fn index_files_and_folders(folder_path: &PathBuf) -> Result<HashSet<FileItem>> {
+pub fn index_files_and_folders(
+    folder_path: &PathBuf,
+    extensions: &MediaExtensions,
+) -> Result<HashSet<FileItem>> {
     let mut folder_id: usize = 0;
     let mut file_id: usize = 0;
     let mut file_paths = HashSet::new();
 
+    // Other templates than class folders put organized files anywhere
+    let organized = organized_files(folder_path).unwrap_or_else(|e| {
+        log::warn!("Failed to read the organize journal: {}", e);
+        HashSet::new()
+    });
     for entry in WalkDir::new(folder_path)
         .sort_by_file_name()
         .into_iter()
@@ -184,7 +213,11 @@ pub fn index_files_and_folders(folder_path: &PathBuf) -> Result<HashSet<FileItem
         if entry.file_type().is_dir() {
             folder_id += 1;
         } else if entry.file_type().is_file() {
-            if is_video_photo(entry.path()) {
+            let placed = entry
+                .path()
+                .strip_prefix(folder_path)
+                .is_ok_and(|path| organized.contains(path));
+            if extensions.kind(entry.path()).is_some() && !placed {
                 file_paths.insert(FileItem::new(
                     folder_id,
                     file_id,
@@ -199,18 +232,6 @@ pub fn index_files_and_folders(folder_path: &PathBuf) -> Result<HashSet<FileItem
     Ok(file_paths)
 }
 
-fn is_video_photo(path: &Path) -> bool {
-    if let Some(extension) = path.extension() {
-        match extension.to_str().unwrap().to_lowercase().as_str() {
-            "mp4" | "avi" | "mkv" | "mov" => true,
-            "jpg" | "jpeg" | "png" => true,
-            _ => false,
-        }
-    } else {
-        false
-    }
-}
-
 // EP availability check
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -251,6 +272,13 @@ pub struct ModelConfig {
     pub classes: BTreeSet<String>,
     pub url: Option<String>,
     pub md5: Option<String>,
+    /// Output layout, guessed from `name` when missing
+    #[serde(default)]
+    pub architecture: Option<Architecture>,
+    #[serde(default)]
+    pub tensors: TensorConfig,
+    #[serde(default)]
+    pub preprocess: Preprocess,
 }
 
 impl PartialEq for ModelConfig {
@@ -261,6 +289,9 @@ impl PartialEq for ModelConfig {
             && self.classes == other.classes
             && self.url == other.url
             && self.md5 == other.md5
+            && self.architecture == other.architecture
+            && self.tensors == other.tensors
+            && self.preprocess == other.preprocess
     }
 }
 
@@ -275,6 +306,11 @@ impl ModelConfig {
         }
         class_map
     }
+
+    pub fn architecture(&self) -> Architecture {
+        self.architecture
+            .unwrap_or_else(|| Architecture::from_model_name(&self.name))
+    }
 }
 
 pub fn load_model_config<P: AsRef<Path>>(config: P) -> Result<ModelConfig> {
@@ -285,3 +321,11 @@ pub fn load_model_config<P: AsRef<Path>>(config: P) -> Result<ModelConfig> {
     model_config.path = base_dir.join(&model_config.path);
     Ok(model_config)
 }
+
+/// Lowercase hex md5 of a file, as listed in model tomls.
+pub fn file_md5<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Md5::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -43,14 +43,22 @@ export interface DownloadProgress {
     error?: string;
 }
 
+export interface OrganizeSummary {
+    moved: number;
+    skipped: number;
+    failed: number;
+}
+
 export const detectStatus = $state({
     progress: 0,
     isProcessing: false,
+    isPaused: false,
     showConfig: false,
     configIconAnimating: false,
     showPassword: false,
     isOrganizing: false,
     isUndoOrganizing: false,
+    organizeDryRun: false,
 });
 
 export type Ep =
@@ -76,16 +84,25 @@ export interface DetectOptions {
     guess: boolean;
 }
 
+interface SequenceOptions {
+    gap: number;
+    folderGaps: Record<string, number>;
+    burst: number;
+}
+
 interface ConfigOptions {
     confidenceThreshold: number;
     iouThreshold: number;
-    exportFormat: "Json" | "Csv"; // 可以使用联合类型限制可选值
+    exportFormat: "Json" | "Csv" | "MegaDetector" | "CocoCameraTraps" | "CamtrapDp"; // 可以使用联合类型限制可选值
     bufferPath: string | null;
     bufferSize: number;
     checkPoint: number;
     maxFrames: number;
     iframeOnly: boolean;
     batchSize: number;
+    organizeMode: "Move" | "Copy" | "Hardlink" | "Symlink";
+    organizeTemplate: string | null;
+    sequence: SequenceOptions;
 }
 
 // 定义主配置接口
@@ -120,6 +137,13 @@ export const config = $state<Config>({
         maxFrames: 3,
         iframeOnly: true,
         batchSize: 2,
+        organizeMode: "Move",
+        organizeTemplate: null,
+        sequence: {
+            gap: 60,
+            folderGaps: {},
+            burst: 3,
+        },
     },
     firstRun: true,
 });
@@ -9,6 +9,7 @@ import {
     models,
     type ModelConfig,
     type DownloadProgress,
+    type OrganizeSummary,
 } from "./store.svelte";
 import {
     readDir,
@@ -21,7 +22,7 @@ import { resourceDir, join } from "@tauri-apps/api/path";
 import { open } from "@tauri-apps/plugin-dialog";
 import { invoke } from "@tauri-apps/api/core";
 import { load } from "@tauri-apps/plugin-store";
-import { Command, open as openFile } from "@tauri-apps/plugin-shell";
+import { open as openFile } from "@tauri-apps/plugin-shell";
 import { unwrapFunctionStore, format } from "svelte-i18n";
 import { listen } from "@tauri-apps/api/event";
 import { Window } from "@tauri-apps/api/window";
@@ -91,7 +92,7 @@ export const selectFolder = async () =>
 
 export const selectResumePath = async () =>
     (config.detectOptions.resumePath = await selectPath({
-        filters: [{ name: "Result file", extensions: ["json", "csv"] }],
+        filters: [{ name: "Result file", extensions: ["jsonl", "json", "csv"] }],
         title: "Select result file",
     }));
 
@@ -118,7 +119,10 @@ export async function loadConfig() {
         const store = await load("store.json", { autoSave: false });
         const config_stored = (await store.get("config")) as Config;
         if (config_stored) {
-            config.configOptions = config_stored.configOptions;
+            config.configOptions = {
+                ...config.configOptions,
+                ...config_stored.configOptions,
+            };
             config.detectOptions = config_stored.detectOptions;
             config.firstRun = config_stored.firstRun;
         }
@@ -158,93 +162,87 @@ export async function startProcessing() {
     }
 }
 
+export async function cancelProcessing() {
+    try {
+        await invoke("cancel_detection");
+        detectStatus.isPaused = false;
+    } catch (err) {
+        console.error("Cancel failed:", err);
+    }
+}
+
+export async function togglePauseProcessing() {
+    try {
+        if (detectStatus.isPaused) {
+            await invoke("resume_detection");
+            detectStatus.isPaused = false;
+        } else {
+            await invoke("pause_detection");
+            detectStatus.isPaused = true;
+        }
+    } catch (err) {
+        console.error("Pause/resume failed:", err);
+    }
+}
+
+const organizeOptions = () => ({
+    guess: config.detectOptions.guess,
+    mode: config.configOptions.organizeMode,
+    template: config.configOptions.organizeTemplate?.trim() || null,
+});
+
 export async function organize() {
-    let command;
     const resultFile = `${config.detectOptions.selectedFolder}/result${
-        config.configOptions.exportFormat === "Json" ? ".json" : ".csv"
+        config.configOptions.exportFormat === "Csv" ? ".csv" : ".json"
     }`;
-    const logFile = `${config.detectOptions.selectedFolder}/organize.log`;
-    if (config.detectOptions.guess) {
-        command = Command.sidecar(
-            "binaries/organize",
-            [
-                "--result",
-                resultFile,
-                "--mode",
-                "guess",
-                "--log-level",
-                "INFO",
-                "--log-file",
-                logFile,
-            ],
-            { encoding: "utf8" }
-        );
-    } else {
-        command = Command.sidecar(
-            "binaries/organize",
-            [
-                "--result",
-                resultFile,
-                "--mode",
-                "default",
-                "--log-level",
-                "INFO",
-                "--log-file",
-                logFile,
-            ],
-            { encoding: "utf8" }
-        );
-    }
     detectStatus.isOrganizing = true;
-    const output = await command.execute();
-    if (output.code !== 0) {
-        detectStatus.isOrganizing = false;
+    try {
+        if (detectStatus.organizeDryRun) {
+            const preview = await invoke<{ summary: string }>(
+                "preview_organize",
+                {
+                    resultPath: resultFile,
+                    options: organizeOptions(),
+                }
+            );
+            showDialog($format("dialog.title.Preview"), preview.summary);
+            return;
+        }
+        const summary = await invoke<OrganizeSummary>("organize_media", {
+            resultPath: resultFile,
+            options: organizeOptions(),
+        });
         showDialog(
-            $format("dialog.title.Error"),
-            `${$format("dialog.message.organizeFailed")}${logFile}`
+            $format("dialog.title.Organize"),
+            `${$format("dialog.message.organizeComplete")}${summary.moved}`
         );
-    } else {
-        detectStatus.isOrganizing = false;
+    } catch (err) {
         showDialog(
-            $format("dialog.title.Organize"),
-            `${$format("dialog.message.organizeComplete")}${logFile}`
+            $format("dialog.title.Error"),
+            `${$format("dialog.message.organizeFailed")}${err}`
         );
+    } finally {
+        detectStatus.isOrganizing = false;
     }
 }
 
 export async function undo() {
-    const resultFile = `${config.detectOptions.selectedFolder}/result${
-        config.configOptions.exportFormat === "Json" ? ".json" : ".csv"
-    }`;
-    const logFile = `${config.detectOptions.selectedFolder}/organize.log`;
-    const command = Command.sidecar(
-        "binaries/organize",
-        [
-            "--result",
-            resultFile,
-            "--mode",
-            "undo",
-            "--log-level",
-            "INFO",
-            "--log-file",
-            logFile,
-        ],
-        { encoding: "utf8" }
-    );
     detectStatus.isUndoOrganizing = true;
-    const output = await command.execute();
-    if (output.code !== 0) {
-        detectStatus.isUndoOrganizing = false;
+    try {
+        const summary = await invoke<OrganizeSummary>("undo_organize", {
+            folder: config.detectOptions.selectedFolder,
+        });
         showDialog(
-            $format("dialog.title.Error"),
-            `${$format("dialog.message.undoFailed")}${logFile}`
+            $format("dialog.title.Undo"),
+            `${$format("dialog.message.undoComplete")}${summary.moved}`
         );
-    } else {
-        detectStatus.isUndoOrganizing = false;
+    } catch (err) {
         showDialog(
-            $format("dialog.title.Undo"),
-            `${$format("dialog.message.undoComplete")}${logFile}`
+            $format("dialog.title.Error"),
+            `${$format("dialog.message.undoFailed")}${err}`
         );
+    } finally {
+        detectStatus.isUndoOrganizing = false;
     }
 }
 